@@ -1,4 +1,3 @@
-use crate::dt::{ValueObject};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum OperatorData {
@@ -30,27 +29,118 @@ pub enum ValueData {
     Int(i64),
     Float(f64),
     String(String),
+    Bool(bool),
+    None,
 }
 
+/// `#`开头的行注释
 #[derive(Debug, PartialEq, Clone)]
-pub enum Node {
+pub struct Comment {
+    pub line: usize,
+    /// `#`之后的内容
+    pub text: String,
+    /// 是否跟在同一行的语句后面
+    pub trailing: bool,
+}
+
+/// 节点在源代码中的位置
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Span {
+    /// 源文件编号, 由运行环境分配
+    pub file: usize,
+    /// 字节范围
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// 从self开始到other结束
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum NodeKind {
     Name(String),
     Value(ValueData),
     List(Vec<Node>),
+    /// `[element for $item in source if condition]`
+    ListComp {
+        element: Box<Node>,
+        item_var_name: String,
+        source_exp: Box<Node>,
+        condition: Option<Box<Node>>,
+    },
     Expr(Box<(Node, OperatorData, Node)>),
+    Index {
+        value: Box<Node>,
+        index: Box<Node>,
+    },
+    Slice {
+        value: Box<Node>,
+        start: Option<Box<Node>>,
+        end: Option<Box<Node>>,
+    },
     SetAttr {
         name: String,
         value: Box<Node>,
     },
+    Local {
+        name: String,
+        value: Option<Box<Node>>,
+    },
+    Global {
+        name: String,
+        value: Option<Box<Node>>,
+    },
+    SetIndex {
+        target: Box<Node>,
+        index: Box<Node>,
+        value: Box<Node>,
+    },
+    /// `$name ?= value`, 变量未定义时才赋值
+    SetDefault {
+        name: String,
+        value: Box<Node>,
+    },
+    /// `target += value`, target是变量或者下标
+    AddAssign {
+        target: Box<Node>,
+        value: Box<Node>,
+    },
+    Call {
+        name: String,
+        args: Vec<Node>,
+    },
+    NativeCall {
+        name: String,
+        args: Vec<Node>,
+    },
     Command {
         command: String,
         args: Vec<Node>,
+        stdin: Option<Box<Node>>,
     },
     Target {
         name: String,
         require: Vec<String>,
         body: Vec<Node>,
     },
+    Function {
+        name: String,
+        params: Vec<String>,
+        body: Vec<Node>,
+    },
+    Return(Option<Box<Node>>),
     If {
         if_node: Box<(Node, Vec<Node>)>,
         elif_nodes: Vec<(Node, Vec<Node>)>,
@@ -61,15 +151,116 @@ pub enum Node {
         source_exp: Box<Node>,
         body: Vec<Node>,
     },
+    While {
+        check_exp: Box<Node>,
+        body: Vec<Node>,
+    },
+    Break,
+    Continue,
+    Try {
+        body: Vec<Node>,
+        /// `except`之后用于保存错误的变量名
+        except_name: Option<String>,
+        except_body: Option<Vec<Node>>,
+        finally_body: Option<Vec<Node>>,
+    },
+    Raise(Box<Node>),
+    Include(Box<Node>),
+    Import {
+        path: Box<Node>,
+        name: String,
+    },
     Module {
         body: Vec<Node>,
+        /// 注释等不影响执行的内容, 供格式化工具还原代码
+        trivia: Vec<Comment>,
     },
 }
 
-impl<'a> Node {
+impl Node {
+    pub fn new(kind: NodeKind, span: Span) -> Node {
+        Node { kind, span }
+    }
     pub fn create_expr(left_node: Node, op: OperatorData, right_node: Node) -> Node {
-        Node::Expr(Box::from((left_node, op, right_node)))
+        let span = left_node.span.to(right_node.span);
+        Node::new(NodeKind::Expr(Box::from((left_node, op, right_node))), span)
+    }
+    /// 去掉整棵树的位置信息, 只比较语法结构时使用
+    pub fn without_span(mut self) -> Node {
+        self.clear_span();
+        self
+    }
+    fn clear_span(&mut self) {
+        self.span = Span::default();
+        let clear_all = |nodes: &mut Vec<Node>| nodes.iter_mut().for_each(Node::clear_span);
+        match &mut self.kind {
+            NodeKind::Name(_) | NodeKind::Value(_) | NodeKind::Break | NodeKind::Continue => {}
+            NodeKind::List(items) => clear_all(items),
+            NodeKind::ListComp { element, source_exp, condition, .. } => {
+                element.clear_span();
+                source_exp.clear_span();
+                condition.iter_mut().for_each(|v| v.clear_span());
+            }
+            NodeKind::Expr(v) => {
+                v.0.clear_span();
+                v.2.clear_span();
+            }
+            NodeKind::Index { value, index } => {
+                value.clear_span();
+                index.clear_span();
+            }
+            NodeKind::Slice { value, start, end } => {
+                value.clear_span();
+                start.iter_mut().chain(end.iter_mut()).for_each(|v| v.clear_span());
+            }
+            NodeKind::SetAttr { value, .. } | NodeKind::SetDefault { value, .. } => value.clear_span(),
+            NodeKind::Local { value, .. } | NodeKind::Global { value, .. } | NodeKind::Return(value) => {
+                value.iter_mut().for_each(|v| v.clear_span());
+            }
+            NodeKind::SetIndex { target, index, value } => {
+                target.clear_span();
+                index.clear_span();
+                value.clear_span();
+            }
+            NodeKind::AddAssign { target, value } => {
+                target.clear_span();
+                value.clear_span();
+            }
+            NodeKind::Call { args, .. } | NodeKind::NativeCall { args, .. } => clear_all(args),
+            NodeKind::Command { args, stdin, .. } => {
+                clear_all(args);
+                stdin.iter_mut().for_each(|v| v.clear_span());
+            }
+            NodeKind::Target { body, .. } | NodeKind::Function { body, .. } | NodeKind::Module { body, .. } => clear_all(body),
+            NodeKind::If { if_node, elif_nodes, else_node } => {
+                for (check, body) in std::iter::once(&mut **if_node).chain(elif_nodes.iter_mut()) {
+                    check.clear_span();
+                    clear_all(body);
+                }
+                else_node.iter_mut().for_each(clear_all);
+            }
+            NodeKind::For { source_exp, body, .. } => {
+                source_exp.clear_span();
+                clear_all(body);
+            }
+            NodeKind::While { check_exp, body } => {
+                check_exp.clear_span();
+                clear_all(body);
+            }
+            NodeKind::Try { body, except_body, finally_body, .. } => {
+                clear_all(body);
+                except_body.iter_mut().chain(finally_body.iter_mut()).for_each(clear_all);
+            }
+            NodeKind::Raise(value) | NodeKind::Include(value) => value.clear_span(),
+            NodeKind::Import { path, .. } => path.clear_span(),
+        }
+    }
+}
+
+/// 没有位置信息的节点
+impl From<NodeKind> for Node {
+    fn from(kind: NodeKind) -> Self {
+        Node::new(kind, Span::default())
     }
 }
 
-impl<'a> ValueObject for Node {}
\ No newline at end of file
@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use crate::rt::RunSpace;
+use crate::dt::{TResult, ErrorKind, Value, TList, TPath};
+
+fn check_args(name: &str, args: &[Value], min: usize, max: usize) -> TResult<()> {
+    if args.len() < min || args.len() > max {
+        let expected = if min == max {
+            format!("{}", min)
+        } else if max == usize::MAX {
+            format!("at least {}", min)
+        } else {
+            format!("{} to {}", min, max)
+        };
+        let meg = format!("{}() takes {} arguments but {} were given", name, expected, args.len());
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+    Ok(())
+}
+
+fn arg_str(v: &Value) -> TResult<String> {
+    v.as_str().map(str::to_string).ok_or_else(|| ErrorKind::type_err("str", v))
+}
+
+fn arg_int(v: &Value) -> TResult<i64> {
+    v.as_int().ok_or_else(|| ErrorKind::type_err("int", v))
+}
+
+fn arg_list(v: &Value) -> TResult<TList> {
+    v.as_list().map(|v| v.clone()).ok_or_else(|| ErrorKind::type_err("list", v))
+}
+
+/// 路径参数可以是路径也可以是字符串
+fn arg_path(v: &Value) -> TResult<PathBuf> {
+    if let Some(v) = v.downcast_ref::<TPath>() {
+        return Ok(v.path.clone());
+    }
+    arg_str(v).map(PathBuf::from)
+}
+
+fn wrap_os_str(v: Option<&OsStr>) -> Value {
+    match v {
+        Some(v) => Value::String(v.to_string_lossy().to_string()),
+        None => Value::None,
+    }
+}
+
+fn wrap_str_list(v: Vec<&str>) -> Value {
+    let v: TList = v.into_iter()
+        .map(Value::from)
+        .collect();
+    Value::from(v)
+}
+
+/// 比较两个数字或两个字符串的大小
+fn compare(left: &Value, right: &Value) -> TResult<Ordering> {
+    if let (Some(l), Some(r)) = (left.as_str(), right.as_str()) {
+        return Ok(l.cmp(r));
+    }
+    match (left.as_f64(), right.as_f64()) {
+        (Some(l), Some(r)) => l.partial_cmp(&r).ok_or_else(|| ErrorKind::unsupported_operand("<", left, right)),
+        _ => Err(ErrorKind::unsupported_operand("<", left, right)),
+    }
+}
+
+fn t_len(args: Vec<Value>) -> TResult<Value> {
+    check_args("len", &args, 1, 1)?;
+    let v = match &args[0] {
+        Value::String(v) => v.chars().count(),
+        Value::List(v) => v.read().unwrap().len(),
+        v => return Err(ErrorKind::type_err("str or list", v)),
+    };
+    Ok(Value::from(v as i64))
+}
+
+fn t_str(args: Vec<Value>) -> TResult<Value> {
+    check_args("str", &args, 1, 1)?;
+    Ok(Value::from(args[0].to_str()?))
+}
+
+fn t_int(args: Vec<Value>) -> TResult<Value> {
+    check_args("int", &args, 1, 1)?;
+    let v = match &args[0] {
+        Value::Int(v) => *v,
+        Value::Float(v) => v.trunc() as i64,
+        Value::Bool(v) => *v as i64,
+        Value::String(v) => v.trim().parse::<i64>().map_err(|_| {
+            let meg = format!("invalid literal for int(): {:?}", v);
+            ErrorKind::make_run_err(meg.as_str())
+        })?,
+        v => return Err(ErrorKind::type_err("int, float, bool or str", v)),
+    };
+    Ok(Value::from(v))
+}
+
+fn t_float(args: Vec<Value>) -> TResult<Value> {
+    check_args("float", &args, 1, 1)?;
+    let v = match &args[0] {
+        Value::Int(v) => *v as f64,
+        Value::Float(v) => *v,
+        Value::String(v) => v.trim().parse::<f64>().map_err(|_| {
+            let meg = format!("invalid literal for float(): {:?}", v);
+            ErrorKind::make_run_err(meg.as_str())
+        })?,
+        v => return Err(ErrorKind::type_err("int, float or str", v)),
+    };
+    Ok(Value::from(v))
+}
+
+fn t_split(args: Vec<Value>) -> TResult<Value> {
+    check_args("split", &args, 1, 2)?;
+    let s = arg_str(&args[0])?;
+    match args.get(1) {
+        Some(sep) => {
+            let sep = arg_str(sep)?;
+            if sep.is_empty() {
+                return Err(ErrorKind::make_run_err("split() separator is empty"));
+            }
+            Ok(wrap_str_list(s.split(sep.as_str()).collect()))
+        }
+        None => Ok(wrap_str_list(s.split_whitespace().collect())),
+    }
+}
+
+fn t_join(args: Vec<Value>) -> TResult<Value> {
+    check_args("join", &args, 1, 2)?;
+    let list = arg_list(&args[0])?;
+    let sep = match args.get(1) {
+        Some(sep) => arg_str(sep)?,
+        None => String::new(),
+    };
+    let mut items = Vec::new();
+    for i in list {
+        items.push(i.to_str()?);
+    }
+    Ok(Value::from(items.join(sep.as_str())))
+}
+
+fn t_replace(args: Vec<Value>) -> TResult<Value> {
+    check_args("replace", &args, 3, 3)?;
+    let s = arg_str(&args[0])?;
+    let from = arg_str(&args[1])?;
+    let to = arg_str(&args[2])?;
+    Ok(Value::from(s.replace(from.as_str(), to.as_str())))
+}
+
+fn t_strip(args: Vec<Value>) -> TResult<Value> {
+    check_args("strip", &args, 1, 1)?;
+    let s = arg_str(&args[0])?;
+    Ok(Value::from(s.trim().to_string()))
+}
+
+fn t_upper(args: Vec<Value>) -> TResult<Value> {
+    check_args("upper", &args, 1, 1)?;
+    let s = arg_str(&args[0])?;
+    Ok(Value::from(s.to_uppercase()))
+}
+
+fn t_lower(args: Vec<Value>) -> TResult<Value> {
+    check_args("lower", &args, 1, 1)?;
+    let s = arg_str(&args[0])?;
+    Ok(Value::from(s.to_lowercase()))
+}
+
+fn t_starts_with(args: Vec<Value>) -> TResult<Value> {
+    check_args("starts_with", &args, 2, 2)?;
+    let s = arg_str(&args[0])?;
+    let prefix = arg_str(&args[1])?;
+    Ok(Value::from(s.starts_with(prefix.as_str())))
+}
+
+fn t_ends_with(args: Vec<Value>) -> TResult<Value> {
+    check_args("ends_with", &args, 2, 2)?;
+    let s = arg_str(&args[0])?;
+    let suffix = arg_str(&args[1])?;
+    Ok(Value::from(s.ends_with(suffix.as_str())))
+}
+
+fn t_contains(args: Vec<Value>) -> TResult<Value> {
+    check_args("contains", &args, 2, 2)?;
+    let v = match &args[0] {
+        Value::String(s) => s.contains(arg_str(&args[1])?.as_str()),
+        Value::List(list) => {
+            let mut found = false;
+            for i in list.read().unwrap().iter() {
+                if i.eq(&args[1])? {
+                    found = true;
+                    break;
+                }
+            }
+            found
+        }
+        v => return Err(ErrorKind::type_err("str or list", v)),
+    };
+    Ok(Value::from(v))
+}
+
+/// range()最多生成的元素个数, 避免一次分配过多内存
+const RANGE_LIMIT: i128 = 10_000_000;
+
+fn t_range(args: Vec<Value>) -> TResult<Value> {
+    check_args("range", &args, 1, 3)?;
+    let (start, end) = match args.len() {
+        1 => (0, arg_int(&args[0])?),
+        _ => (arg_int(&args[0])?, arg_int(&args[1])?),
+    };
+    let step = match args.get(2) {
+        Some(step) => arg_int(step)?,
+        None => 1,
+    };
+    if step == 0 {
+        return Err(ErrorKind::make_run_err("range() step must not be zero"));
+    }
+    let length = ((end as i128 - start as i128) + step as i128 - step.signum() as i128) / step as i128;
+    if length > RANGE_LIMIT {
+        let meg = format!("range() would produce {} items, more than the limit of {}", length, RANGE_LIMIT);
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+    let mut v = TList::new();
+    let mut i = Some(start);
+    while let Some(n) = i.filter(|n| (step > 0 && *n < end) || (step < 0 && *n > end)) {
+        v.push(Value::Int(n));
+        i = n.checked_add(step);
+    }
+    Ok(Value::from(v))
+}
+
+fn t_sorted(args: Vec<Value>) -> TResult<Value> {
+    check_args("sorted", &args, 1, 1)?;
+    let mut list = arg_list(&args[0])?;
+    // 先检查所有元素都可以比较, 排序过程中不再处理错误
+    for i in list.windows(2) {
+        compare(&i[0], &i[1])?;
+    }
+    list.sort_by(|l, r| compare(l, r).unwrap_or(Ordering::Equal));
+    Ok(Value::from(list))
+}
+
+fn t_reversed(args: Vec<Value>) -> TResult<Value> {
+    check_args("reversed", &args, 1, 1)?;
+    if let Some(s) = args[0].as_str() {
+        let v: String = s.chars().rev().collect();
+        return Ok(Value::from(v));
+    }
+    let mut list = arg_list(&args[0])?;
+    list.reverse();
+    Ok(Value::from(list))
+}
+
+fn t_path(args: Vec<Value>) -> TResult<Value> {
+    check_args("path", &args, 1, usize::MAX)?;
+    let mut v = PathBuf::new();
+    for i in args.iter() {
+        v.push(arg_path(i)?);
+    }
+    Ok(TPath::wrap(v))
+}
+
+fn t_parent(args: Vec<Value>) -> TResult<Value> {
+    check_args("parent", &args, 1, 1)?;
+    let v = arg_path(&args[0])?;
+    Ok(v.parent().map_or(Value::None, TPath::wrap))
+}
+
+fn t_file_name(args: Vec<Value>) -> TResult<Value> {
+    check_args("file_name", &args, 1, 1)?;
+    Ok(wrap_os_str(arg_path(&args[0])?.file_name()))
+}
+
+fn t_stem(args: Vec<Value>) -> TResult<Value> {
+    check_args("stem", &args, 1, 1)?;
+    Ok(wrap_os_str(arg_path(&args[0])?.file_stem()))
+}
+
+fn t_extension(args: Vec<Value>) -> TResult<Value> {
+    check_args("extension", &args, 1, 1)?;
+    Ok(wrap_os_str(arg_path(&args[0])?.extension()))
+}
+
+fn t_with_extension(args: Vec<Value>) -> TResult<Value> {
+    check_args("with_extension", &args, 2, 2)?;
+    let v = arg_path(&args[0])?;
+    Ok(TPath::wrap(v.with_extension(arg_str(&args[1])?)))
+}
+
+fn t_absolute(args: Vec<Value>) -> TResult<Value> {
+    check_args("absolute", &args, 1, 1)?;
+    Ok(TPath::wrap(TPath::absolute(&arg_path(&args[0])?)?))
+}
+
+fn t_relative_to(args: Vec<Value>) -> TResult<Value> {
+    check_args("relative_to", &args, 2, 2)?;
+    let v = TPath::relative_to(&arg_path(&args[0])?, &arg_path(&args[1])?)?;
+    Ok(TPath::wrap(v))
+}
+
+fn t_normalize(args: Vec<Value>) -> TResult<Value> {
+    check_args("normalize", &args, 1, 1)?;
+    Ok(TPath::wrap(TPath::normalize(&arg_path(&args[0])?)))
+}
+
+/// 向运行空间注册所有内置函数
+pub fn load(space: &mut RunSpace) {
+    space.add_native_function("len", t_len);
+    space.add_native_function("str", t_str);
+    space.add_native_function("int", t_int);
+    space.add_native_function("float", t_float);
+    space.add_native_function("split", t_split);
+    space.add_native_function("join", t_join);
+    space.add_native_function("replace", t_replace);
+    space.add_native_function("strip", t_strip);
+    space.add_native_function("upper", t_upper);
+    space.add_native_function("lower", t_lower);
+    space.add_native_function("starts_with", t_starts_with);
+    space.add_native_function("ends_with", t_ends_with);
+    space.add_native_function("contains", t_contains);
+    space.add_native_function("range", t_range);
+    space.add_native_function("sorted", t_sorted);
+    space.add_native_function("reversed", t_reversed);
+    space.add_native_function("path", t_path);
+    space.add_native_function("parent", t_parent);
+    space.add_native_function("file_name", t_file_name);
+    space.add_native_function("stem", t_stem);
+    space.add_native_function("extension", t_extension);
+    space.add_native_function("with_extension", t_with_extension);
+    space.add_native_function("absolute", t_absolute);
+    space.add_native_function("relative_to", t_relative_to);
+    space.add_native_function("normalize", t_normalize);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rt::{eval_code, RunSpace};
+
+    fn eval(code: &str) -> String {
+        let mut space = RunSpace::default();
+        eval_code(code, &mut space).unwrap().to_repr_str().unwrap()
+    }
+
+    #[test]
+    fn test_builtin() {
+        assert_eq!(eval(r#"len([1, 2, 3]) + len("中文")"#), "5");
+        assert_eq!(eval(r#"str(1) + str(1.5)"#), r#""11.5""#);
+        assert_eq!(eval(r#"int("42") + int(2.9)"#), "44");
+        assert_eq!(eval(r#"float("0.5")"#), "0.5");
+        assert_eq!(eval(r#"split("a b  c")"#), r#"["a", "b", "c"]"#);
+        assert_eq!(eval(r#"split("a,b", ",")"#), r#"["a", "b"]"#);
+        assert_eq!(eval(r#"join(["a", 1, 2.5], "-")"#), r#""a-1-2.5""#);
+        assert_eq!(eval(r#"replace("a.c", ".", "b")"#), r#""abc""#);
+        assert_eq!(eval(r#"upper(strip("  ab "))"#), r#""AB""#);
+        assert_eq!(eval(r#"lower("AB")"#), r#""ab""#);
+        assert_eq!(eval(r#"starts_with("tentacle", "ten")"#), "true");
+        assert_eq!(eval(r#"ends_with("tentacle", "ten")"#), "false");
+        assert_eq!(eval(r#"contains("tentacle", "tac")"#), "true");
+        assert_eq!(eval(r#"contains([1, "2"], 2)"#), "false");
+        assert_eq!(eval(r#"range(3)"#), "[0, 1, 2]");
+        assert_eq!(eval(r#"range(5, 0, -2)"#), "[5, 3, 1]");
+        assert_eq!(eval(r#"range(3, 5, 10)"#), "[3]");
+        assert_eq!(eval(r#"range(0, 5, -1)"#), "[]");
+        // 最后一步溢出时停止, 不会panic
+        assert_eq!(eval(r#"range(9223372036854775806, 9223372036854775807, 2)"#), "[9223372036854775806]");
+        assert_eq!(eval(r#"range(-9223372036854775807, -9223372036854775807 - 1, -2)"#), "[-9223372036854775807]");
+        assert_eq!(eval(r#"sorted([3, 1.5, 2])"#), "[1.5, 2, 3]");
+        assert_eq!(eval(r#"sorted(["b", "a"])"#), r#"["a", "b"]"#);
+        assert_eq!(eval(r#"reversed([1, 2])"#), "[2, 1]");
+        assert_eq!(eval(r#"reversed("ab")"#), r#""ba""#);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path() {
+        assert_eq!(eval(r#"path("target") / "release" / path("tentacle.exe")"#), r#"path("target/release/tentacle.exe")"#);
+        assert_eq!(eval(r#"path("a", "b") / "/usr""#), r#"path("/usr")"#);
+        assert_eq!(eval(r#"parent(path("src/main.rs"))"#), r#"path("src")"#);
+        assert_eq!(eval(r#"parent("/")"#), "None");
+        assert_eq!(eval(r#"file_name(path("src/main.rs"))"#), r#""main.rs""#);
+        assert_eq!(eval(r#"stem("src/main.rs") + extension("src/main.rs")"#), r#""mainrs""#);
+        assert_eq!(eval(r#"extension("Makefile")"#), "None");
+        assert_eq!(eval(r#"with_extension(path("src/main.rs"), "o")"#), r#"path("src/main.o")"#);
+        assert_eq!(eval(r#"normalize("./a//b/../c/.")"#), r#"path("a/c")"#);
+        assert_eq!(eval(r#"normalize("/../a/..")"#), r#"path("/")"#);
+        assert_eq!(eval(r#"relative_to("/a/b/c", "/a")"#), r#"path("b/c")"#);
+        assert_eq!(eval(r#"relative_to("src/main.rs", "target/debug")"#), r#"path("../../src/main.rs")"#);
+        assert_eq!(eval(r#"absolute("a") == path(absolute(".") / "a")"#), "true");
+        assert_eq!(eval(r#""-L" + path("lib") / "x""#), r#""-Llib/x""#);
+        assert_eq!(eval(r#"path("a/./b") == path("a/b")"#), "true");
+    }
+
+    #[test]
+    fn test_builtin_error() {
+        let mut space = RunSpace::default();
+        assert!(eval_code("len(1)", &mut space).is_err());
+        assert!(eval_code("len([], [])", &mut space).is_err());
+        assert!(eval_code(r#"int("x")"#, &mut space).is_err());
+        assert!(eval_code("range(1, 2, 0)", &mut space).is_err());
+        assert!(eval_code("range(9223372036854775807)", &mut space).is_err());
+        assert!(eval_code("path()", &mut space).is_err());
+        assert!(eval_code("path(1)", &mut space).is_err());
+        assert!(eval_code(r#"sorted([1, "a"])"#, &mut space).is_err());
+        assert!(eval_code("not_a_builtin(1)", &mut space).is_err());
+    }
+}
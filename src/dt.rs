@@ -1,30 +1,26 @@
-use std::any::{Any, TypeId};
-use std::borrow::{Borrow, BorrowMut};
-use std::cell::{Ref, RefCell, RefMut};
+use std::any::Any;
+use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
-use std::path::Iter;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
 use crate::ast;
 
-pub trait ValueObject: Any {
-    fn tid(&self) -> TypeId {
-        self.type_id()
-    }
-    // fn downcast_ref<T>(&self) -> Option<&T> {
-    //     let any_self = self as &dyn Any;
-    //     any_self.downcast_ref::<T>()
-    // }
-    fn add(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> { Err(ErrorKind::FunctionNotImplemented) }
-    fn sub(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> { Err(ErrorKind::FunctionNotImplemented) }
-    fn mul(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> { Err(ErrorKind::FunctionNotImplemented) }
-    fn div(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> { Err(ErrorKind::FunctionNotImplemented) }
+/// 由宿主定义的对象, 例如target, 函数, 模块和路径
+///
+/// 常用的值直接用`Value`中的枚举表示, 只有这些对象需要动态分派
+pub trait ValueObject: Any + Send + Sync {
+    /// 类型名, 用于错误信息
+    fn type_name(&self) -> &'static str { "object" }
+
+    fn add(&self, _right: &Value) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
+    fn sub(&self, _right: &Value) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
+    fn mul(&self, _right: &Value) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
+    fn div(&self, _right: &Value) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
 
-    fn eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> { Err(ErrorKind::FunctionNotImplemented) }
-    fn not_eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> { Ok(!(self.eq(right)?)) }
+    fn eq(&self, _right: &Value) -> TResult<bool> { Err(ErrorKind::FunctionNotImplemented) }
+    fn not_eq(&self, right: &Value) -> TResult<bool> { Ok(!(self.eq(right)?)) }
 
     fn to_str(&self) -> TResult<String> {
-        // format!("{}", self.type_id())
-        // let p = unsafe { self as *const i32 as usize };
         Ok(format!("object <{:p}>", self))
     }
     fn to_repr_str(&self) -> TResult<String> {
@@ -33,23 +29,42 @@ pub trait ValueObject: Any {
     }
     fn to_bool(&self) -> TResult<bool> { Ok(true) }
 
-    fn get_iter(&self) -> TResult<WrapValueObject> { Err(ErrorKind::FunctionNotImplemented) }
-    fn iter_next(&mut self) -> TResult<Option<WrapValueObject>> { Err(ErrorKind::FunctionNotImplemented) }
+    fn get_iter(&self) -> TResult<TListIter> { Err(ErrorKind::FunctionNotImplemented) }
+
+    fn get_index(&self, _index: i64) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
+    fn set_index(&self, _index: i64, _value: Value) -> TResult<()> { Err(ErrorKind::FunctionNotImplemented) }
+    fn get_slice(&self, _start: Option<i64>, _end: Option<i64>) -> TResult<Value> { Err(ErrorKind::FunctionNotImplemented) }
+
+    /// `$v.name`形式的属性访问
+    fn get_attr(&self, _name: &str) -> Option<Value> { None }
 }
 
-pub fn downcast_ref<T: 'static>(v: &Box<dyn ValueObject>) -> Option<&T> {
-    if TypeId::of::<T>() == v.tid() {
-        Some(unsafe { &*(v as *const dyn Any as *const Box<T>) })
+/// 将下标(可以为负数, 表示从末尾开始计数)转换为实际位置
+pub fn resolve_index(index: i64, length: usize) -> TResult<usize> {
+    let v = if index < 0 { index + length as i64 } else { index };
+    if v < 0 || v >= length as i64 {
+        Err(ErrorKind::IndexError { index, length })
     } else {
-        None
+        Ok(v as usize)
     }
 }
 
+/// 将切片范围转换为实际位置, 超出范围的部分会被截断
+pub fn resolve_slice(start: Option<i64>, end: Option<i64>, length: usize) -> (usize, usize) {
+    let clamp = |v: i64| {
+        let v = if v < 0 { v + length as i64 } else { v };
+        v.clamp(0, length as i64) as usize
+    };
+    let start = start.map_or(0, clamp);
+    let end = end.map_or(length, clamp);
+    (start, end.max(start))
+}
+
 impl Display for dyn ValueObject {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.to_str() {
             Ok(obj) => {
-                f.write_str(format!("{}", obj).as_str())?;
+                f.write_str(obj.to_string().as_str())?;
                 Ok(())
             }
             Err(_) => {
@@ -73,387 +88,704 @@ impl Debug for dyn ValueObject {
     }
 }
 
+/// 脚本中的值
 #[derive(Debug, Clone)]
-pub struct WrapValueObject {
-    obj: Arc<RefCell<Box<dyn ValueObject>>>,
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    /// 列表可以通过下标修改, 复制值时共享同一个列表
+    List(Arc<RwLock<TList>>),
+    Object(Arc<dyn ValueObject>),
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self { Value::Bool(v) }
 }
 
-impl WrapValueObject {
-    pub fn from_box(obj: Box<dyn ValueObject>) -> Self {
-        WrapValueObject {
-            obj: Arc::from(RefCell::from(obj)),
+impl From<i64> for Value {
+    fn from(v: i64) -> Self { Value::Int(v) }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self { Value::Float(v) }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self { Value::String(v) }
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self { Value::String(v.to_string()) }
+}
+
+impl From<TList> for Value {
+    fn from(v: TList) -> Self { Value::List(Arc::new(RwLock::new(v))) }
+}
+
+/// 宿主对象没有实现的操作当作类型错误
+fn or_type_err<T>(v: TResult<T>, err: impl FnOnce() -> ErrorKind) -> TResult<T> {
+    match v {
+        Err(ErrorKind::FunctionNotImplemented) => Err(err()),
+        v => v,
+    }
+}
+
+fn integer_overflow() -> ErrorKind {
+    ErrorKind::make_run_err("integer overflow")
+}
+
+impl Value {
+    pub fn object(v: impl ValueObject) -> Self {
+        Value::Object(Arc::new(v))
+    }
+    pub fn downcast_ref<T: ValueObject>(&self) -> Option<&T> {
+        match self {
+            Value::Object(v) => (&**v as &dyn Any).downcast_ref::<T>(),
+            _ => None,
         }
     }
-    pub fn unwrap(&self) -> Ref<'_, Box<dyn ValueObject>> {
-        RefCell::borrow(self.obj.borrow())
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "none",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::String(_) => "str",
+            Value::List(_) => "list",
+            Value::Object(v) => v.type_name(),
+        }
     }
-    pub fn unwrap_mut(&self) -> RefMut<'_, Box<dyn ValueObject>> {
-        RefCell::borrow_mut(self.obj.borrow())
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(v) => Some(v.as_str()),
+            _ => None,
+        }
     }
-    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
-        let v = &*self.unwrap();
-        if TypeId::of::<T>() == v.tid() {
-            Some(unsafe { &*(v as *const dyn Any as *const Box<T>) })
-        } else {
-            None
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+    /// 整数和浮点数都可以当作浮点数使用
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v),
+            _ => None,
         }
     }
-    pub fn t_add(&self, right: Self) -> TResult<WrapValueObject> {
-        // let v = right.unwrap();
-        // v.add()
-        self.unwrap().add(&*right.unwrap())
+    pub fn as_list(&self) -> Option<RwLockReadGuard<'_, TList>> {
+        match self {
+            Value::List(v) => Some(v.read().unwrap()),
+            _ => None,
+        }
     }
-    pub fn t_sub(&self, right: Self) -> TResult<WrapValueObject> {
-        self.unwrap().sub(&*right.unwrap())
+
+    pub fn t_add(&self, right: &Value) -> TResult<Value> {
+        let v = match (self, right) {
+            (Value::Int(l), Value::Int(r)) => l.checked_add(*r).map(Value::Int).ok_or_else(integer_overflow),
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                Ok(Value::Float(self.as_f64().unwrap() + right.as_f64().unwrap()))
+            }
+            (Value::String(l), Value::String(_) | Value::Int(_) | Value::Float(_)) => {
+                Ok(Value::String(format!("{}{}", l, right.to_str()?)))
+            }
+            (Value::String(l), Value::Object(r)) if right.downcast_ref::<TPath>().is_some() => {
+                Ok(Value::String(format!("{}{}", l, r.to_str()?)))
+            }
+            (Value::List(l), Value::List(r)) => {
+                let mut v = l.read().unwrap().clone();
+                v.extend(r.read().unwrap().iter().cloned());
+                Ok(Value::from(v))
+            }
+            (Value::Object(l), _) => l.add(right),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::unsupported_operand("+", self, right))
     }
-    pub fn t_mul(&self, right: Self) -> TResult<WrapValueObject> {
-        self.unwrap().mul(&*right.unwrap())
+    pub fn t_sub(&self, right: &Value) -> TResult<Value> {
+        let v = match (self, right) {
+            (Value::Int(l), Value::Int(r)) => l.checked_sub(*r).map(Value::Int).ok_or_else(integer_overflow),
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                Ok(Value::Float(self.as_f64().unwrap() - right.as_f64().unwrap()))
+            }
+            (Value::Object(l), _) => l.sub(right),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::unsupported_operand("-", self, right))
     }
-    pub fn t_div(&self, right: Self) -> TResult<WrapValueObject> {
-        self.unwrap().div(&*right.unwrap())
+    pub fn t_mul(&self, right: &Value) -> TResult<Value> {
+        let v = match (self, right) {
+            (Value::Int(l), Value::Int(r)) => l.checked_mul(*r).map(Value::Int).ok_or_else(integer_overflow),
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                Ok(Value::Float(self.as_f64().unwrap() * right.as_f64().unwrap()))
+            }
+            (Value::Object(l), _) => l.mul(right),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::unsupported_operand("*", self, right))
+    }
+    pub fn t_div(&self, right: &Value) -> TResult<Value> {
+        let v = match (self, right) {
+            // 整数相除的结果也是浮点数
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                Ok(Value::Float(self.as_f64().unwrap() / right.as_f64().unwrap()))
+            }
+            (Value::Object(l), _) => l.div(right),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::unsupported_operand("/", self, right))
     }
 
-    pub fn t_eq(&self, right: Self) -> TResult<WrapValueObject> {
-        let v = self.unwrap().eq(&*right.unwrap())?;
-        Ok(WrapValueObject::from_box(Box::new(v)))
+    pub fn eq(&self, right: &Value) -> TResult<bool> {
+        self.eq_in(right, &mut Vec::new())
     }
-    pub fn t_not_eq(&self, right: Self) -> TResult<WrapValueObject> {
-        let v = self.unwrap().eq(&*right.unwrap())?;
-        Ok(WrapValueObject::from_box(Box::new(v)))
+    /// comparing里是正在比较的列表, 列表包含自己时不会无限递归
+    fn eq_in(&self, right: &Value, comparing: &mut Vec<(usize, usize)>) -> TResult<bool> {
+        match (self, right) {
+            (Value::None, Value::None) => Ok(true),
+            (Value::Bool(l), Value::Bool(r)) => Ok(l == r),
+            (Value::Int(l), Value::Int(r)) => Ok(l == r),
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+                Ok(self.as_f64() == right.as_f64())
+            }
+            (Value::String(l), Value::String(r)) => Ok(l == r),
+            (Value::List(l), Value::List(r)) if Arc::ptr_eq(l, r) => Ok(true),
+            (Value::List(l), Value::List(r)) => {
+                let pair = (Arc::as_ptr(l) as usize, Arc::as_ptr(r) as usize);
+                if comparing.contains(&pair) {
+                    return Ok(true);
+                }
+                // 先复制再比较, 不会在持有读锁时再对同一个列表加读锁
+                let (l, r) = (l.read().unwrap().clone(), r.read().unwrap().clone());
+                if l.len() != r.len() {
+                    return Ok(false);
+                }
+                comparing.push(pair);
+                let mut result = Ok(true);
+                for (l, r) in l.iter().zip(r.iter()) {
+                    match l.eq_in(r, comparing) {
+                        Ok(true) => {}
+                        v => {
+                            result = v;
+                            break;
+                        }
+                    }
+                }
+                comparing.pop();
+                result
+            }
+            (Value::Object(l), _) => l.eq(right),
+            _ => Ok(false),
+        }
+    }
+    pub fn t_eq(&self, right: &Value) -> TResult<Value> {
+        Ok(Value::Bool(self.eq(right)?))
+    }
+    pub fn t_not_eq(&self, right: &Value) -> TResult<Value> {
+        let v = match self {
+            Value::Object(l) => l.not_eq(right)?,
+            _ => !self.eq(right)?,
+        };
+        Ok(Value::Bool(v))
     }
-    pub fn t_get_iter(&self) -> TResult<WrapValueObject> { self.unwrap().get_iter() }
-    pub fn t_iter_next(&mut self) -> TResult<Option<WrapValueObject>> { self.unwrap_mut().iter_next() }
 
+    pub fn t_get_iter(&self) -> TResult<TListIter> {
+        let v = match self {
+            // 迭代开始时复制列表, 迭代过程中修改列表不影响迭代
+            Value::List(v) => Ok(TListIter::new(v.read().unwrap().clone())),
+            Value::Object(v) => v.get_iter(),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::TypeError(format!("'{}' object is not iterable", self.type_name())))
+    }
+    pub fn t_get_index(&self, index: i64) -> TResult<Value> {
+        let v = match self {
+            Value::String(v) => {
+                let chars: Vec<char> = v.chars().collect();
+                Ok(Value::String(chars[resolve_index(index, chars.len())?].to_string()))
+            }
+            Value::List(v) => {
+                let v = v.read().unwrap();
+                Ok(v[resolve_index(index, v.len())?].clone())
+            }
+            Value::Object(v) => v.get_index(index),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::TypeError(format!("'{}' object is not subscriptable", self.type_name())))
+    }
+    pub fn t_set_index(&self, index: i64, value: Value) -> TResult<()> {
+        let v = match self {
+            Value::List(v) => {
+                let mut v = v.write().unwrap();
+                let index = resolve_index(index, v.len())?;
+                v[index] = value;
+                Ok(())
+            }
+            Value::Object(v) => v.set_index(index, value),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::TypeError(format!("'{}' object does not support item assignment", self.type_name())))
+    }
+    pub fn t_get_slice(&self, start: Option<i64>, end: Option<i64>) -> TResult<Value> {
+        let v = match self {
+            Value::String(v) => {
+                let chars: Vec<char> = v.chars().collect();
+                let (start, end) = resolve_slice(start, end, chars.len());
+                Ok(Value::String(chars[start..end].iter().collect()))
+            }
+            Value::List(v) => {
+                let v = v.read().unwrap();
+                let (start, end) = resolve_slice(start, end, v.len());
+                Ok(Value::from(v[start..end].to_vec()))
+            }
+            Value::Object(v) => v.get_slice(start, end),
+            _ => Err(ErrorKind::FunctionNotImplemented),
+        };
+        or_type_err(v, || ErrorKind::TypeError(format!("'{}' object is not subscriptable", self.type_name())))
+    }
+    pub fn get_attr(&self, name: &str) -> Option<Value> {
+        match self {
+            Value::Object(v) => v.get_attr(name),
+            _ => None,
+        }
+    }
 
-    pub fn to_str(&self) -> TResult<String> { self.unwrap().to_str() }
-    pub fn to_repr_str(&self) -> TResult<String> { self.unwrap().to_repr_str() }
-    pub fn to_bool(&self) -> TResult<bool> { self.unwrap().to_bool() }
+    pub fn to_str(&self) -> TResult<String> {
+        self.to_str_in(&mut Vec::new())
+    }
+    /// printing里是正在输出的列表, 列表包含自己时输出`[...]`
+    fn to_str_in(&self, printing: &mut Vec<usize>) -> TResult<String> {
+        let v = match self {
+            Value::None => "None".to_string(),
+            Value::Bool(v) => v.to_string(),
+            Value::Int(v) => v.to_string(),
+            Value::Float(v) => v.to_string(),
+            Value::String(v) => v.clone(),
+            Value::List(v) => {
+                let id = Arc::as_ptr(v) as usize;
+                if printing.contains(&id) {
+                    return Ok("[...]".to_string());
+                }
+                let list = v.read().unwrap().clone();
+                printing.push(id);
+                let items = list.iter().map(|i| i.to_repr_str_in(printing)).collect::<TResult<Vec<_>>>();
+                printing.pop();
+                format!("[{}]", items?.join(", "))
+            }
+            Value::Object(v) => v.to_str()?,
+        };
+        Ok(v)
+    }
+    #[allow(dead_code)]
+    pub fn to_repr_str(&self) -> TResult<String> {
+        self.to_repr_str_in(&mut Vec::new())
+    }
+    fn to_repr_str_in(&self, printing: &mut Vec<usize>) -> TResult<String> {
+        match self {
+            Value::String(v) => Ok(format!(r#""{}""#, v)),
+            Value::Object(v) => v.to_repr_str(),
+            _ => self.to_str_in(printing),
+        }
+    }
+    pub fn to_bool(&self) -> TResult<bool> {
+        let v = match self {
+            Value::None => false,
+            Value::Bool(v) => *v,
+            Value::Int(v) => *v != 0,
+            Value::Float(v) => *v != 0.0,
+            Value::String(v) => !v.is_empty(),
+            Value::List(v) => !v.read().unwrap().is_empty(),
+            Value::Object(v) => v.to_bool()?,
+        };
+        Ok(v)
+    }
 }
 
 pub type TResult<T> = Result<T, ErrorKind>;
 
+/// 出错的位置, 用于像编译器一样显示出错的代码
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    /// 出错部分的长度, 按字符计数
+    pub width: usize,
+    /// 出错的那一行代码
+    pub snippet: String,
+}
+
+impl Location {
+    /// 根据源代码中的字节偏移计算行号和列号, 出错部分不会超出当前行
+    pub fn from_offset(source: &str, offset: usize, len: usize) -> Self {
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+        let end = (offset + len).min(line_end);
+        Self {
+            file: String::new(),
+            line: source[..offset].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            width: source[offset..end].trim_end().chars().count().max(1),
+            snippet: source[line_start..line_end].trim_end().to_string(),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let file = if self.file.is_empty() { "<input>" } else { self.file.as_str() };
+        write!(f, "{}:{}:{}", file, self.line, self.column)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum ErrorKind {
-    Syntax {
-        error_line_number: usize,
-    },
+    Syntax(String),
     FunctionNotImplemented,
     RuntimeError(String),
     CommandError(String, String),
     NameError(String),
-    TypeError,
+    TypeError(String),
+    IndexError {
+        index: i64,
+        length: usize,
+    },
+    /// 命令执行失败, 被信号终止时没有退出码
+    ExitError {
+        command: String,
+        code: Option<i64>,
+    },
+    /// 附带出错位置和target调用链的错误
+    Traced {
+        error: Box<ErrorKind>,
+        location: Option<Box<Location>>,
+        targets: Vec<String>,
+    },
+    /// 一起报告的多个错误, 比如一个文件中的所有语法错误
+    Multiple(Vec<ErrorKind>),
 }
 
 impl ErrorKind {
     pub fn make_run_err(message: &str) -> Self {
         Self::RuntimeError(message.to_string())
     }
-}
-
-impl ValueObject for ErrorKind {
-    fn to_str(&self) -> TResult<String> {
-        let v = match self {
-            ErrorKind::Syntax { error_line_number } => {
-                format!("SyntaxError: line {}", error_line_number)
-            }
-            ErrorKind::FunctionNotImplemented => {
-                format!("FunctionNotImplementedError")
-            }
-            ErrorKind::RuntimeError(v) => {
-                format!("RuntimeError: {}", v)
-            }
-            ErrorKind::CommandError(command, message) => {
-                format!("CommandError({}): {}", command, message)
-            }
-            ErrorKind::NameError(name) => {
-                format!("NameError: name '{}' is not defined", name)
-            }
-            ErrorKind::TypeError => {
-                format!("TypeError")
-            }
-        };
-        Ok(v)
+    /// 参数类型不符合要求
+    pub fn type_err(expected: &str, v: &Value) -> Self {
+        Self::TypeError(format!("expected {}, got {}", expected, v.type_name()))
     }
-}
-
-
-impl ValueObject for i64 {
-    fn add(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) + (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) + (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
-        }
+    /// 两个值之间不支持的运算
+    pub fn unsupported_operand(op: &str, left: &Value, right: &Value) -> Self {
+        let meg = format!("unsupported operand types for {}: '{}' and '{}'", op, left.type_name(), right.type_name());
+        Self::TypeError(meg)
     }
-    fn sub(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) - (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) - (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+    /// 去掉位置和调用链之后的原始错误
+    pub fn root(&self) -> &ErrorKind {
+        match self {
+            ErrorKind::Traced { error, .. } => error.root(),
+            _ => self,
         }
     }
-    fn mul(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) * (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) * (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            ErrorKind::Traced { location, .. } => location.as_deref(),
+            ErrorKind::Multiple(errors) => errors.first().and_then(ErrorKind::location),
+            _ => None,
         }
     }
-    fn div(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = ((*self) as f64) / ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) / (*right);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+    pub fn targets(&self) -> &[String] {
+        match self {
+            ErrorKind::Traced { targets, .. } => targets,
+            _ => &[],
         }
     }
-    fn eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            Ok((*self) == (*right))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            Ok(((*self) as f64) == (*right))
-        } else {
-            Ok(false)
+    fn into_traced(self) -> (ErrorKind, Option<Box<Location>>, Vec<String>) {
+        match self {
+            ErrorKind::Traced { error, location, targets } => (*error, location, targets),
+            _ => (self, None, Vec::new()),
         }
     }
-    fn to_str(&self) -> TResult<String> {
-        Ok(format!("{}", self))
+    /// 记录出错位置, 已经有位置时保留最初的位置
+    pub fn at(self, location: Location) -> Self {
+        let (error, old, targets) = self.into_traced();
+        let location = Some(old.unwrap_or_else(|| Box::new(location)));
+        ErrorKind::Traced { error: Box::new(error), location, targets }
     }
-    fn to_repr_str(&self) -> TResult<String> {
-        self.to_str()
-    }
-    fn to_bool(&self) -> TResult<bool> {
-        Ok((*self) != (0 as i64))
-    }
-}
-
-impl ValueObject for f64 {
-    fn add(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) + ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) + ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+    /// 给还没有文件名的位置加上文件名
+    pub fn in_file(self, file: &str) -> Self {
+        if let ErrorKind::Multiple(errors) = self {
+            return ErrorKind::Multiple(errors.into_iter().map(|e| e.in_file(file)).collect());
         }
-    }
-    fn sub(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) - ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) - ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+        let (error, mut location, targets) = self.into_traced();
+        if let Some(v) = location.as_mut().filter(|v| v.file.is_empty()) {
+            v.file = file.to_string();
         }
-    }
-    fn mul(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = (*self) * ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) * ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+        if location.is_none() && targets.is_empty() {
+            return error;
+        }
+        ErrorKind::Traced { error: Box::new(error), location, targets }
+    }
+    /// 错误离开target时把target加到调用链的最前面
+    pub fn in_target(self, target: &str) -> Self {
+        let (error, location, mut targets) = self.into_traced();
+        targets.insert(0, target.to_string());
+        ErrorKind::Traced { error: Box::new(error), location, targets }
+    }
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ErrorKind::Syntax(_) => "SyntaxError",
+            ErrorKind::FunctionNotImplemented => "FunctionNotImplementedError",
+            ErrorKind::RuntimeError(_) => "RuntimeError",
+            ErrorKind::CommandError(..) => "CommandError",
+            ErrorKind::NameError(_) => "NameError",
+            ErrorKind::TypeError(_) => "TypeError",
+            ErrorKind::IndexError { .. } => "IndexError",
+            ErrorKind::ExitError { .. } => "ExitError",
+            ErrorKind::Traced { error, .. } => error.kind(),
+            ErrorKind::Multiple(errors) => errors.first().map_or("Error", ErrorKind::kind),
         }
     }
-    fn div(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let v = ((*self) as f64) / ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            let v = ((*self) as f64) / ((*right) as f64);
-            Ok(WrapValueObject::from_box(Box::new(v)))
-        } else {
-            Err(ErrorKind::FunctionNotImplemented)
+    pub fn message(&self) -> String {
+        match self {
+            ErrorKind::FunctionNotImplemented => String::new(),
+            ErrorKind::Syntax(v) | ErrorKind::RuntimeError(v) | ErrorKind::TypeError(v) => v.clone(),
+            ErrorKind::CommandError(_, message) => message.clone(),
+            ErrorKind::NameError(name) => format!("name '{}' is not defined", name),
+            ErrorKind::IndexError { index, length } => {
+                format!("index {} out of range for length {}", index, length)
+            }
+            ErrorKind::ExitError { command, code: Some(code) } => {
+                format!("command {} exited with code {}", command, code)
+            }
+            ErrorKind::ExitError { command, code: None } => {
+                format!("command {} was terminated by a signal", command)
+            }
+            ErrorKind::Traced { error, .. } => error.message(),
+            ErrorKind::Multiple(errors) => format!("{} errors", errors.len()),
         }
     }
-    fn eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> {
-        if let Some(right) = downcast_ref::<i64>(right) {
-            Ok((*self) == ((*right) as f64))
-        } else if let Some(right) = downcast_ref::<f64>(right) {
-            Ok((*self) == (*right))
-        } else {
-            Ok(false)
+    /// 像编译器一样显示错误, 在出错的代码下面用`^`标记
+    ///
+    /// ```text
+    /// error[TypeError]: unsupported operand types for +: 'int' and 'list'
+    ///  --> main.tentacle:3:10
+    ///   |
+    /// 3 |     $a = 1 + [2]
+    ///   |          ^^^^^^^
+    ///   = target: build -> clean
+    /// ```
+    pub fn render(&self) -> String {
+        if let ErrorKind::Multiple(errors) = self {
+            let mut v: Vec<String> = errors.iter().map(ErrorKind::render).collect();
+            v.push(format!("error: found {} errors", errors.len()));
+            return v.join("\n\n");
+        }
+        let mut v = format!("error[{}]", self.kind());
+        let message = self.message();
+        if !message.is_empty() {
+            v.push_str(": ");
+            v.push_str(message.as_str());
+        }
+        let location = self.location();
+        let gutter = location.map_or(1, |i| i.line.to_string().len());
+        let pad = " ".repeat(gutter);
+        if let Some(location) = location {
+            v.push_str(format!("\n{}--> {}", pad, location).as_str());
+            if !location.snippet.is_empty() {
+                // 标记前的缩进保留原来的制表符, 保证在终端中对齐
+                let indent: String = location.snippet.chars()
+                    .take(location.column - 1)
+                    .map(|i| if i == '\t' { '\t' } else { ' ' })
+                    .collect();
+                v.push_str(format!("\n{} |", pad).as_str());
+                v.push_str(format!("\n{} | {}", location.line, location.snippet).as_str());
+                v.push_str(format!("\n{} | {}{}", pad, indent, "^".repeat(location.width)).as_str());
+            }
+        }
+        let mut chain = self.targets().to_vec();
+        if let ErrorKind::ExitError { command, .. } | ErrorKind::CommandError(command, _) = self.root() {
+            if !chain.is_empty() {
+                chain.push(command.clone());
+            }
+        }
+        if !chain.is_empty() {
+            v.push_str(format!("\n{} = target: {}", pad, chain.join(" -> ")).as_str());
         }
+        v
     }
+}
+
+impl ValueObject for ErrorKind {
+    fn type_name(&self) -> &'static str { "error" }
     fn to_str(&self) -> TResult<String> {
-        Ok(format!("{}", self))
-    }
-    fn to_repr_str(&self) -> TResult<String> {
-        self.to_str()
+        let v = match self.root() {
+            ErrorKind::CommandError(command, message) => {
+                format!("{}({}): {}", self.kind(), command, message)
+            }
+            ErrorKind::FunctionNotImplemented => self.kind().to_string(),
+            _ => format!("{}: {}", self.kind(), self.message()),
+        };
+        Ok(v)
     }
-    fn to_bool(&self) -> TResult<bool> {
-        Ok((*self) != (0 as f64))
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        let v = match name {
+            "kind" => Value::from(self.kind()),
+            "message" => Value::String(self.message()),
+            "code" => match self.root() {
+                ErrorKind::ExitError { code: Some(code), .. } => Value::Int(*code),
+                _ => Value::None,
+            },
+            _ => return None,
+        };
+        Some(v)
     }
 }
 
-impl ValueObject for String {
-    fn add(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<String>(right) {
-            let mut v = String::new();
-            v.push_str(self.as_str());
-            v.push_str(right.as_str());
-            return Ok(WrapValueObject::from_box(Box::new(v)));
-        }
-        if let Some(right) = downcast_ref::<i64>(right) {
-            let mut v = String::new();
-            v.push_str(self.as_str());
-            v.push_str(right.to_string().as_str());
-            return Ok(WrapValueObject::from_box(Box::new(v)));
+/// 文件路径, 用`/`拼接
+#[derive(Debug, PartialEq, Clone)]
+pub struct TPath {
+    pub(crate) path: PathBuf,
+}
+
+impl TPath {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+    pub fn wrap(path: impl Into<PathBuf>) -> Value {
+        Value::object(Self::new(path))
+    }
+    /// 去掉`.`和多余的分隔符并合并`..`, 不访问文件系统
+    pub fn normalize(path: &Path) -> PathBuf {
+        let mut v = PathBuf::new();
+        for i in path.components() {
+            match i {
+                Component::CurDir => {}
+                Component::ParentDir => match v.components().next_back() {
+                    Some(Component::Normal(_)) => { v.pop(); }
+                    // 根目录的上一级还是根目录
+                    Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                    _ => v.push(".."),
+                },
+                i => v.push(i),
+            }
         }
-        if let Some(right) = downcast_ref::<f64>(right) {
-            let mut v = String::new();
-            v.push_str(self.as_str());
-            v.push_str(right.to_string().as_str());
-            return Ok(WrapValueObject::from_box(Box::new(v)));
+        if v.as_os_str().is_empty() {
+            v.push(".");
         }
-        return Err(ErrorKind::FunctionNotImplemented);
-    }
-    fn eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> {
-        if let Some(right) = downcast_ref::<Self>(right) {
-            Ok((*self) == (*right))
+        v
+    }
+    pub fn absolute(path: &Path) -> TResult<PathBuf> {
+        let v = std::path::absolute(path).map_err(|e| {
+            let meg = format!("absolute({}) failed: {}", path.display(), e);
+            ErrorKind::make_run_err(meg.as_str())
+        })?;
+        Ok(Self::normalize(&v))
+    }
+    /// 从base到path的相对路径, path不在base中时用`..`向上走
+    pub fn relative_to(path: &Path, base: &Path) -> TResult<PathBuf> {
+        let (path, base) = if path.is_absolute() == base.is_absolute() {
+            (Self::normalize(path), Self::normalize(base))
         } else {
-            Ok(false)
+            (Self::absolute(path)?, Self::absolute(base)?)
+        };
+        let path: Vec<Component> = path.components().collect();
+        let base: Vec<Component> = base.components().filter(|i| *i != Component::CurDir).collect();
+        let common = path.iter().zip(base.iter()).take_while(|(a, b)| a == b).count();
+        if base[common..].contains(&Component::ParentDir) {
+            let meg = "relative_to() can not walk up from a base outside the current directory";
+            return Err(ErrorKind::make_run_err(meg));
         }
-    }
-    fn to_str(&self) -> TResult<String> {
-        Ok(self.clone())
+        let mut v = PathBuf::new();
+        for _ in common..base.len() {
+            v.push("..");
+        }
+        for i in &path[common..] {
+            v.push(i);
+        }
+        Ok(Self::normalize(&v))
     }
 }
 
-impl ValueObject for bool {
-    fn to_str(&self) -> TResult<String> {
-        Ok(format!("{}", self))
-    }
-    fn to_repr_str(&self) -> TResult<String> {
-        self.to_str()
+impl ValueObject for TPath {
+    fn type_name(&self) -> &'static str { "path" }
+    fn div(&self, right: &Value) -> TResult<Value> {
+        if let Some(right) = right.downcast_ref::<TPath>() {
+            return Ok(TPath::wrap(self.path.join(&right.path)));
+        }
+        if let Some(right) = right.as_str() {
+            return Ok(TPath::wrap(self.path.join(right)));
+        }
+        Err(ErrorKind::FunctionNotImplemented)
     }
-    fn to_bool(&self) -> TResult<bool> {
-        Ok(*self)
+    fn eq(&self, right: &Value) -> TResult<bool> {
+        if let Some(right) = right.downcast_ref::<Self>() {
+            Ok(TPath::normalize(&self.path) == TPath::normalize(&right.path))
+        } else {
+            Ok(false)
+        }
     }
-}
-
-#[derive(Debug, PartialEq, Clone, Default)]
-pub struct TNone;
-
-impl ValueObject for TNone {
-    fn eq(&self, right: &Box<dyn ValueObject>) -> TResult<bool> { Ok(self.tid() == right.tid()) }
     fn to_str(&self) -> TResult<String> {
-        Ok(format!("None"))
+        Ok(self.path.to_string_lossy().to_string())
     }
     fn to_repr_str(&self) -> TResult<String> {
-        self.to_str()
+        Ok(format!("path({:?})", self.to_str()?))
     }
-    fn to_bool(&self) -> TResult<bool> { Ok(false) }
 }
 
-impl TNone {
-    pub fn a_none() -> WrapValueObject {
-        WrapValueObject::from_box(Box::from(TNone::default()))
-    }
-}
-
-// pub struct IterObject{}
-
-pub type TList = Vec<WrapValueObject>;
+pub type TList = Vec<Value>;
 
+/// 列表的迭代器, 宿主对象也可以通过它支持`for`循环
 #[derive(Debug, Clone)]
 pub struct TListIter {
     list: TList,
     this: usize,
 }
 
-impl ValueObject for TList {
-    fn add(&self, right: &Box<dyn ValueObject>) -> TResult<WrapValueObject> {
-        if let Some(right) = downcast_ref::<Self>(right) {
-            let mut v = self.clone();
-            for i in right {
-                v.push(i.clone())
-            }
-            return Ok(WrapValueObject::from_box(Box::new(v)));
-        }
-        return Err(ErrorKind::FunctionNotImplemented);
-    }
-    fn to_str(&self) -> TResult<String> {
-        let mut v = String::new();
-        v.push('[');
-        let mut self_iter = self.iter();
-        if let Some(i) = self_iter.next() {
-            v.push_str(i.to_repr_str()?.as_str());
-            for i in self_iter {
-                v.push_str(", ");
-                v.push_str(i.to_repr_str()?.as_str());
-            }
-        }
-        v.push(']');
-        Ok(v)
-    }
-    fn to_repr_str(&self) -> TResult<String> {
-        self.to_str()
-    }
-    fn get_iter(&self) -> TResult<WrapValueObject> {
-        Ok(WrapValueObject::from_box(Box::new(TListIter {
-            list: self.clone(),
-            this: 0,
-        })))
+impl TListIter {
+    pub fn new(list: TList) -> Self {
+        Self { list, this: 0 }
     }
 }
 
-impl ValueObject for TListIter {
-    fn get_iter(&self) -> TResult<WrapValueObject> { Ok(WrapValueObject::from_box(Box::new(self.clone()))) }
-    fn iter_next(&mut self) -> TResult<Option<WrapValueObject>> {
-        let v = self.list.get(self.this);
-        match v {
-            Some(v) => {
-                self.this = self.this + 1;
-                Ok(Some(v.clone()))
-            }
-            None => {
-                Ok(None)
-            }
-        }
+impl Iterator for TListIter {
+    type Item = Value;
+    fn next(&mut self) -> Option<Value> {
+        let v = self.list.get(self.this)?;
+        self.this += 1;
+        Some(v.clone())
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Default)]
+/// 全局作用域中的变量, 函数/target/模块通过它找到定义时所在的全局作用域
+pub type TVars = Arc<RwLock<HashMap<String, Value>>>;
+
+#[derive(Debug, Clone, Default)]
 pub struct TTargetObject {
     pub(crate) name: String,
     pub(crate) require: Vec<String>,
     pub(crate) body: Vec<ast::Node>,
+    pub(crate) globals: TVars,
+    /// target定义的位置
+    pub(crate) span: ast::Span,
 }
 
 impl ValueObject for TTargetObject {
+    fn type_name(&self) -> &'static str { "target" }
     fn to_str(&self) -> TResult<String> {
         let mut require_str = "".to_string();
         let mut require_iter = self.require.iter();
         if let Some(i) = require_iter.next() {
-            require_str.push_str(r#"""#);
+            require_str.push('"');
             require_str.push_str(i.as_str());
-            require_str.push_str(r#"""#);
+            require_str.push('"');
             for i in require_iter {
                 require_str.push_str(r#", ""#);
                 require_str.push_str(i.as_str());
-                require_str.push_str(r#"""#);
+                require_str.push('"');
             }
         }
         Ok(format!(r#"TargetObject("{}", body_size={}, require=[{}])"#, self.name, self.body.len(), require_str))
@@ -463,3 +795,64 @@ impl ValueObject for TTargetObject {
     }
 }
 
+
+#[derive(Debug, Clone, Default)]
+pub struct TFunctionObject {
+    pub(crate) name: String,
+    pub(crate) params: Vec<String>,
+    pub(crate) body: Vec<ast::Node>,
+    pub(crate) globals: TVars,
+}
+
+impl ValueObject for TFunctionObject {
+    fn type_name(&self) -> &'static str { "function" }
+    fn to_str(&self) -> TResult<String> {
+        let params: Vec<String> = self.params.iter().map(|i| format!("${}", i)).collect();
+        Ok(format!(r#"FunctionObject("{}", params=[{}])"#, self.name, params.join(", ")))
+    }
+    fn to_repr_str(&self) -> TResult<String> {
+        self.to_str()
+    }
+}
+
+/// 通过`import`导入的模块
+#[derive(Debug, Clone, Default)]
+pub struct TModuleObject {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) globals: TVars,
+}
+
+impl TModuleObject {
+    pub fn get(&self, key: &str) -> Option<Value> {
+        if let Some(v) = self.globals.read().unwrap().get(key) {
+            return Some(v.clone());
+        }
+        let (ns, name) = key.split_once('.')?;
+        let module = self.get(ns)?;
+        let module = module.downcast_ref::<TModuleObject>()?;
+        module.get(name)
+    }
+    pub fn set(&self, key: &str, value: Value) {
+        if let Some((ns, name)) = key.split_once('.') {
+            if let Some(module) = self.get(ns).and_then(|v| v.downcast_ref::<TModuleObject>().cloned()) {
+                module.set(name, value);
+                return;
+            }
+        }
+        self.globals.write().unwrap().insert(key.to_string(), value);
+    }
+}
+
+impl ValueObject for TModuleObject {
+    fn type_name(&self) -> &'static str { "module" }
+    fn to_str(&self) -> TResult<String> {
+        Ok(format!(r#"ModuleObject("{}", path="{}")"#, self.name, self.path))
+    }
+    fn to_repr_str(&self) -> TResult<String> {
+        self.to_str()
+    }
+    fn get_attr(&self, name: &str) -> Option<Value> {
+        self.get(name)
+    }
+}
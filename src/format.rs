@@ -0,0 +1,658 @@
+use std::cell::{Cell, RefCell};
+use crate::ast::{Comment, Node, NodeKind, OperatorData, ValueData};
+use crate::dt::{ErrorKind, TResult};
+use crate::parse;
+
+/// 每层缩进的空格数
+const INDENT: &str = "    ";
+
+/// 格式化一个源文件, 格式化前后的语法树必须相同
+pub fn format_source(source: &str) -> TResult<String> {
+    let ast = parse::parse_source(source, 0)?;
+    let output = Formatter::new(source, &ast).format(&ast);
+    // 防止格式化改变代码的含义, 注释和语句的位置会变化所以只比较语句的结构
+    let body = |node: &Node| match node.clone().without_span().kind {
+        NodeKind::Module { body, .. } => body,
+        kind => vec![Node::from(kind)],
+    };
+    match parse::parse_source(output.as_str(), 0) {
+        Ok(v) if body(&v) == body(&ast) => Ok(output),
+        _ => Err(ErrorKind::make_run_err("formatting would change the meaning of the code")),
+    }
+}
+
+/// 根据语法树重新生成代码, 注释和空行按照在原代码中的行号还原
+pub struct Formatter<'a> {
+    source: &'a str,
+    /// 每一行开头的字节位置
+    line_starts: Vec<usize>,
+    comments: Vec<Comment>,
+    next_comment: usize,
+    output: String,
+    /// 最后输出的内容在原代码中的行号
+    last_line: usize,
+    /// 刚输出了语句块的开头, 不需要空行
+    block_start: bool,
+    /// 正在生成的代码的缩进层数, 用于多行的列表和参数
+    indent: Cell<usize>,
+    /// 已经在多行的列表和参数中输出的注释的下标
+    inline_comments: RefCell<Vec<usize>>,
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(source: &'a str, module: &Node) -> Self {
+        let comments = match &module.kind {
+            NodeKind::Module { trivia, .. } => trivia.clone(),
+            _ => Vec::new(),
+        };
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Formatter {
+            source,
+            line_starts,
+            comments,
+            next_comment: 0,
+            output: String::new(),
+            last_line: 0,
+            block_start: true,
+            indent: Cell::new(0),
+            inline_comments: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn format(mut self, module: &Node) -> String {
+        match &module.kind {
+            NodeKind::Module { body, .. } => self.write_block(body, 0),
+            _ => self.write_statement(module, 0),
+        }
+        self.write_comments_before(usize::MAX, 0);
+        self.output
+    }
+
+    fn line_of(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|i| *i <= offset)
+    }
+
+    fn line_text(&self, line: usize) -> &'a str {
+        let start = self.line_starts[line - 1];
+        let end = self.line_starts.get(line).map_or(self.source.len(), |i| *i);
+        self.source[start..end].trim_end_matches(['\r', '\n'])
+    }
+
+    fn end_line(&self, node: &Node) -> usize {
+        self.line_of(node.span.end.max(node.span.start + 1) - 1)
+    }
+
+    /// 原代码中某行的缩进宽度
+    fn indentation_of(&self, line: usize) -> usize {
+        let text = self.line_text(line);
+        text.len() - text.trim_start_matches([' ', '\t']).len()
+    }
+
+    /// 某行之后第一个既不是空行也不是注释的行
+    fn next_code_line(&self, line: usize) -> usize {
+        (line + 1..=self.line_starts.len())
+            .find(|i| {
+                let text = self.line_text(*i).trim();
+                !text.is_empty() && !text.starts_with('#')
+            })
+            .unwrap_or(usize::MAX)
+    }
+
+    /// 原代码中两行之间有空行时保留一个空行
+    fn separate(&mut self, line: usize) {
+        let blank = (self.last_line + 1..line.min(self.line_starts.len() + 1))
+            .any(|i| self.line_text(i).trim().is_empty());
+        if blank && !self.block_start && !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.block_start = false;
+    }
+
+    /// 下一个还没有输出的注释
+    fn pending_comment(&mut self) -> Option<&Comment> {
+        while self.inline_comments.borrow().contains(&self.next_comment) {
+            self.next_comment += 1;
+        }
+        self.comments.get(self.next_comment)
+    }
+
+    fn write_comment(&mut self, depth: usize) {
+        let comment = self.comments[self.next_comment].clone();
+        self.next_comment += 1;
+        self.separate(comment.line);
+        self.output.push_str(&INDENT.repeat(depth));
+        self.output.push('#');
+        self.output.push_str(comment.text.trim_end());
+        self.output.push('\n');
+        self.last_line = comment.line;
+    }
+
+    /// 输出某行之前还没有输出的注释
+    fn write_comments_before(&mut self, line: usize, depth: usize) {
+        while self.pending_comment().is_some_and(|i| i.line < line) {
+            self.write_comment(depth);
+        }
+    }
+
+    /// 语句块结束后缩进不少于块中语句的注释仍然属于这个语句块
+    fn finish_block(&mut self, body: &[Node], depth: usize) {
+        let (Some(first), Some(last)) = (body.first(), body.last()) else { return };
+        let indentation = self.indentation_of(first.span.line);
+        let boundary = self.next_code_line(self.end_line(last));
+        while let Some(line) = self.pending_comment().map(|i| i.line) {
+            if line >= boundary || self.indentation_of(line) < indentation {
+                break;
+            }
+            self.write_comment(depth);
+        }
+    }
+
+    /// 输出一行代码和跟在这一行后面的注释
+    fn write_line(&mut self, depth: usize, text: &str, end_line: usize) {
+        self.output.push_str(&INDENT.repeat(depth));
+        self.output.push_str(text);
+        while self.pending_comment().is_some_and(|i| i.trailing && i.line <= end_line) {
+            self.output.push_str("  #");
+            self.output.push_str(self.comments[self.next_comment].text.trim_end());
+            self.next_comment += 1;
+        }
+        self.output.push('\n');
+        self.last_line = self.last_line.max(end_line);
+    }
+
+    fn write_block(&mut self, body: &[Node], depth: usize) {
+        for node in body {
+            self.write_comments_before(node.span.line, depth);
+            self.separate(node.span.line);
+            self.write_statement(node, depth);
+        }
+    }
+
+    /// 输出语句块的开头一行和语句块
+    fn write_body(&mut self, header: &str, line: usize, body: &[Node], depth: usize) {
+        let first_line = body.first().map_or(line, |i| i.span.line);
+        self.write_line(depth, header, first_line.max(line + 1) - 1);
+        self.last_line = line;
+        self.block_start = true;
+        self.write_block(body, depth + 1);
+        self.finish_block(body, depth + 1);
+    }
+
+    /// `elif`, `else`这样跟在语句块后面的子句
+    fn write_clause(&mut self, header: &str, previous: &[Node], body: &[Node], depth: usize) {
+        let line = previous.last().map_or(self.last_line, |i| self.end_line(i));
+        let line = self.next_code_line(line);
+        self.write_comments_before(line, depth);
+        self.block_start = true;
+        self.write_body(header, line, body, depth);
+    }
+
+    fn write_statement(&mut self, node: &Node, depth: usize) {
+        let line = node.span.line;
+        self.indent.set(depth);
+        match &node.kind {
+            NodeKind::Target { name, require, body } => {
+                let mut header = format!("target ${}:", name);
+                for i in require {
+                    header.push_str(&format!(" ${}", i));
+                }
+                self.write_body(&header, line, body, depth);
+            }
+            NodeKind::Function { name, params, body } => {
+                let params: Vec<String> = params.iter().map(|i| format!("${}", i)).collect();
+                let header = format!("def ${}({}):", name, params.join(", "));
+                self.write_body(&header, line, body, depth);
+            }
+            NodeKind::If { if_node, elif_nodes, else_node } => {
+                let header = format!("if {}:", self.expr(&if_node.0));
+                self.write_body(&header, line, &if_node.1, depth);
+                let mut previous = &if_node.1;
+                for (check_exp, body) in elif_nodes {
+                    self.indent.set(depth);
+                    let header = format!("elif {}:", self.expr(check_exp));
+                    self.write_clause(&header, previous, body, depth);
+                    previous = body;
+                }
+                if let Some(body) = else_node {
+                    self.write_clause("else:", previous, body, depth);
+                }
+            }
+            NodeKind::For { item_var_name, source_exp, body } => {
+                let header = format!("for ${} in {}:", item_var_name, self.expr(source_exp));
+                self.write_body(&header, line, body, depth);
+            }
+            NodeKind::While { check_exp, body } => {
+                let header = format!("while {}:", self.expr(check_exp));
+                self.write_body(&header, line, body, depth);
+            }
+            NodeKind::Try { body, except_name, except_body, finally_body } => {
+                self.write_body("try:", line, body, depth);
+                let mut previous = body;
+                if let Some(except_body) = except_body {
+                    self.indent.set(depth);
+                    let header = match except_name {
+                        Some(name) => format!("except ${}:", name),
+                        None => "except:".to_string(),
+                    };
+                    self.write_clause(&header, previous, except_body, depth);
+                    previous = except_body;
+                }
+                if let Some(finally_body) = finally_body {
+                    self.write_clause("finally:", previous, finally_body, depth);
+                }
+            }
+            NodeKind::Module { body, .. } => self.write_block(body, depth),
+            _ => {
+                let text = self.line(node);
+                let end_line = self.end_line(node);
+                self.write_line(depth, &text, end_line);
+            }
+        }
+    }
+
+    /// 单行语句
+    fn line(&self, node: &Node) -> String {
+        match &node.kind {
+            NodeKind::SetAttr { name, value } => format!("${} = {}", name, self.expr(value)),
+            NodeKind::SetDefault { name, value } => format!("${} ?= {}", name, self.expr(value)),
+            NodeKind::SetIndex { target, index, value } => {
+                format!("{}[{}] = {}", self.operand(target), self.expr(index), self.expr(value))
+            }
+            NodeKind::AddAssign { target, value } => format!("{} += {}", self.expr(target), self.expr(value)),
+            NodeKind::Local { name, value } => self.declare("local", name, value),
+            NodeKind::Global { name, value } => self.declare("global", name, value),
+            NodeKind::Return(None) => "return".to_string(),
+            NodeKind::Return(Some(value)) => format!("return {}", self.expr(value)),
+            NodeKind::Break => "break".to_string(),
+            NodeKind::Continue => "continue".to_string(),
+            NodeKind::Raise(value) => {
+                // `fail`是`raise`的别名, 保留原代码中的写法
+                let keyword = match self.source.get(node.span.start..) {
+                    Some(v) if v.starts_with("fail") => "fail",
+                    _ => "raise",
+                };
+                format!("{} {}", keyword, self.expr(value))
+            }
+            NodeKind::Include(path) => format!("include {}", self.expr(path)),
+            NodeKind::Import { path, name } => format!("import {} as ${}", self.expr(path), name),
+            _ => self.expr(node),
+        }
+    }
+
+    fn declare(&self, keyword: &str, name: &str, value: &Option<Box<Node>>) -> String {
+        match value {
+            Some(value) => format!("{} ${} = {}", keyword, name, self.expr(value)),
+            None => format!("{} ${}", keyword, name),
+        }
+    }
+
+    fn expr(&self, node: &Node) -> String {
+        match &node.kind {
+            NodeKind::Name(name) => format!("${}", name),
+            NodeKind::Value(value) => {
+                // 字面量保持原样, 不改变数字的进制和字符串的写法
+                if node.span.end > node.span.start {
+                    self.source[node.span.start..node.span.end].to_string()
+                } else {
+                    Formatter::value(value)
+                }
+            }
+            NodeKind::List(items) => format!("[{}]", self.items(node.span.line, items, self.end_line(node))),
+            NodeKind::ListComp { element, item_var_name, source_exp, condition } => {
+                let mut text = format!("[{} for ${} in {}", self.expr(element), item_var_name, self.expr(source_exp));
+                if let Some(condition) = condition {
+                    text.push_str(&format!(" if {}", self.expr(condition)));
+                }
+                text.push(']');
+                text
+            }
+            NodeKind::Expr(expr) => {
+                let (left, op, right) = expr.as_ref();
+                let left = match Formatter::left_needs_parens(left, op) {
+                    true => format!("({})", self.expr(left)),
+                    false => self.expr(left),
+                };
+                let right = match Formatter::right_needs_parens(right, op) {
+                    true => format!("({})", self.expr(right)),
+                    false => self.expr(right),
+                };
+                format!("{} {} {}", left, Formatter::operator(op), right)
+            }
+            NodeKind::Index { value, index } => format!("{}[{}]", self.operand(value), self.expr(index)),
+            NodeKind::Slice { value, start, end } => {
+                let start = start.as_ref().map_or(String::new(), |i| self.expr(i));
+                let end = end.as_ref().map_or(String::new(), |i| self.expr(i));
+                format!("{}[{}:{}]", self.operand(value), start, end)
+            }
+            NodeKind::Call { name, args } => {
+                format!("${}({})", name, self.items(node.span.line, args, self.end_line(node)))
+            }
+            NodeKind::NativeCall { name, args } => {
+                format!("{}({})", name, self.items(node.span.line, args, self.end_line(node)))
+            }
+            NodeKind::Command { command, args, stdin } => {
+                // 原代码中用`\`续行的参数仍然放在下一行
+                let continuation = format!(" \\\n{}", INDENT.repeat(self.indent.get() + 1));
+                let mut text = command.clone();
+                let mut line = node.span.line;
+                for i in args {
+                    text.push_str(if i.span.line > line { &continuation } else { " " });
+                    text.push_str(&self.command_arg(i));
+                    line = self.end_line(i);
+                }
+                if let Some(stdin) = stdin {
+                    text.push_str(" <<<");
+                    text.push_str(if stdin.span.line > line { &continuation } else { " " });
+                    text.push_str(&self.operand(stdin));
+                }
+                text
+            }
+            NodeKind::SetAttr { .. }
+            | NodeKind::SetDefault { .. }
+            | NodeKind::SetIndex { .. }
+            | NodeKind::AddAssign { .. }
+            | NodeKind::Local { .. }
+            | NodeKind::Global { .. }
+            | NodeKind::Return(_)
+            | NodeKind::Break
+            | NodeKind::Continue
+            | NodeKind::Raise(_)
+            | NodeKind::Include(_)
+            | NodeKind::Import { .. } => self.line(node),
+            NodeKind::Target { .. }
+            | NodeKind::Function { .. }
+            | NodeKind::If { .. }
+            | NodeKind::For { .. }
+            | NodeKind::While { .. }
+            | NodeKind::Try { .. }
+            // 语句块只能由write_statement输出, 出现在表达式中时保留原代码
+            | NodeKind::Module { .. } => self.source.get(node.span.start..node.span.end).unwrap_or_default().to_string(),
+        }
+    }
+
+    /// 逗号分隔的元素, 保留原代码中的换行. 括号单独一行时最后一个元素后面加上逗号
+    fn items(&self, open_line: usize, nodes: &[Node], close_line: usize) -> String {
+        if nodes.is_empty() {
+            return String::new();
+        }
+        let depth = self.indent.get();
+        let indent = INDENT.repeat(depth + 1);
+        self.indent.set(depth + 1);
+        let mut text = String::new();
+        let mut line = open_line;
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 {
+                text.push(',');
+            }
+            if node.span.line > line {
+                self.line_break(&mut text, line, node.span.line, &indent, &indent);
+            } else if i > 0 {
+                text.push(' ');
+            }
+            text.push_str(&self.expr(node));
+            line = self.end_line(node);
+        }
+        self.indent.set(depth);
+        if close_line > line {
+            text.push(',');
+            self.line_break(&mut text, line, close_line, &indent, &INDENT.repeat(depth));
+        }
+        text
+    }
+
+    /// 从原代码的from行换到to行, 同时输出from行的行尾注释和两行之间单独一行的注释
+    fn line_break(&self, text: &mut String, from: usize, to: usize, comment_indent: &str, indent: &str) {
+        for (index, comment) in self.comments.iter().enumerate().skip(self.next_comment) {
+            if comment.line >= to {
+                break;
+            }
+            if comment.line < from || (comment.line == from && !comment.trailing) {
+                continue;
+            }
+            if comment.line == from {
+                text.push_str("  #");
+            } else {
+                text.push('\n');
+                text.push_str(comment_indent);
+                text.push('#');
+            }
+            text.push_str(comment.text.trim_end());
+            self.inline_comments.borrow_mut().push(index);
+        }
+        text.push('\n');
+        text.push_str(indent);
+    }
+
+    /// 下标, 命令参数这些位置的表达式需要加上括号
+    fn operand(&self, node: &Node) -> String {
+        match &node.kind {
+            NodeKind::Expr(_) => format!("({})", self.expr(node)),
+            _ => self.expr(node),
+        }
+    }
+
+    /// 命令参数中的内置函数调用和`true`, `false`, `none`不加括号会被当作单词
+    fn command_arg(&self, node: &Node) -> String {
+        let mut value = node;
+        while let NodeKind::Index { value: v, .. } | NodeKind::Slice { value: v, .. } = &value.kind {
+            value = v;
+        }
+        match &value.kind {
+            NodeKind::NativeCall { .. } | NodeKind::Value(ValueData::Bool(_) | ValueData::None) => {
+                format!("({})", self.expr(node))
+            }
+            _ => self.operand(node),
+        }
+    }
+
+    fn left_needs_parens(left: &Node, op: &OperatorData) -> bool {
+        matches!(&left.kind, NodeKind::Expr(v) if v.1.get_priority() < op.get_priority())
+    }
+
+    fn right_needs_parens(right: &Node, op: &OperatorData) -> bool {
+        match &right.kind {
+            NodeKind::Expr(v) => v.1.get_priority() <= op.get_priority() || !Formatter::is_chain(right),
+            _ => false,
+        }
+    }
+
+    /// 右操作数只在是同一优先级的简单运算链时才能省略括号, 和parse_expr的结合方式一致
+    fn is_chain(node: &Node) -> bool {
+        let NodeKind::Expr(expr) = &node.kind else { return true };
+        let (left, op, right) = expr.as_ref();
+        let left = match &left.kind {
+            NodeKind::Expr(v) if v.1.get_priority() == op.get_priority() => Formatter::is_chain(left),
+            NodeKind::Expr(_) => Formatter::left_needs_parens(left, op),
+            _ => true,
+        };
+        // 加了括号的操作数对parse_expr来说是一个整体
+        let right = !matches!(right.kind, NodeKind::Expr(_)) || Formatter::right_needs_parens(right, op);
+        left && right
+    }
+
+    fn operator(op: &OperatorData) -> &'static str {
+        match op {
+            OperatorData::Eq => "==",
+            OperatorData::NotEq => "!=",
+            OperatorData::Add => "+",
+            OperatorData::Sub => "-",
+            OperatorData::Mul => "*",
+            OperatorData::Div => "/",
+        }
+    }
+
+    /// 没有原代码时的字面量写法
+    fn value(value: &ValueData) -> String {
+        match value {
+            ValueData::Int(v) => v.to_string(),
+            ValueData::Float(v) => format!("{:?}", v),
+            ValueData::String(v) => {
+                let mut text = String::from('"');
+                for c in v.chars() {
+                    match c {
+                        '\n' => text.push_str("\\n"),
+                        '\r' => text.push_str("\\r"),
+                        '\t' => text.push_str("\\t"),
+                        '\0' => text.push_str("\\0"),
+                        '\\' => text.push_str("\\\\"),
+                        '"' => text.push_str("\\\""),
+                        c => text.push(c),
+                    }
+                }
+                text.push('"');
+                text
+            }
+            ValueData::Bool(v) => v.to_string(),
+            ValueData::None => "none".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::format::*;
+
+    #[test]
+    fn test_format() {
+        let source = r#"# 开头的注释
+import   "lib.tentacle"   as $lib
+
+
+$a=[1,2 ,  3]   # 行尾
+$b ?= 0x1F
+$c[0]  =  $a[1:] + [ $x*2 for $x in $a if $x!=2 ]
+$f = ls -l "a b" $a[0]  ($a + 1) <<< "input"
+def $add($x,$y):  # 函数
+  # 函数体注释
+  return $x+$y
+target $build:$clean    $lib.dep
+  "doc"
+  for $i in range(10):
+    if $i == 1:
+      continue
+    elif $i==2:
+      break
+
+    # else之前
+    else:  # 否则
+      fail $i
+    # 块末尾
+
+
+  try:
+    message x
+  except $e:
+    raise  $e
+# 结尾"#;
+        let expected = r#"# 开头的注释
+import "lib.tentacle" as $lib
+
+$a = [1, 2, 3]  # 行尾
+$b ?= 0x1F
+$c[0] = $a[1:] + [$x * 2 for $x in $a if $x != 2]
+$f = ls -l "a b" $a[0] ($a + 1) <<< "input"
+def $add($x, $y):  # 函数
+    # 函数体注释
+    return $x + $y
+target $build: $clean $lib.dep
+    "doc"
+    for $i in range(10):
+        if $i == 1:
+            continue
+        elif $i == 2:
+            break
+
+        # else之前
+        else:  # 否则
+            fail $i
+        # 块末尾
+
+    try:
+        message x
+    except $e:
+        raise $e
+# 结尾
+"#;
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+        assert!(format_source("if $a:\n").is_err());
+    }
+
+    #[test]
+    fn test_format_line_breaks() {
+        // 保留列表, 参数和续行的换行以及其中的注释
+        let source = r#"target $build:
+  cc -o main \
+      main.c   \
+      util.c <<< \
+      "x"
+  $flags = [
+      "-O2",  # 优化
+      # 警告
+      "-Wall",
+      [1,
+        2]]
+  $v = len(
+      $flags,  # 参数
+  ) + $f(1,
+          2)
+"#;
+        let expected = r#"target $build:
+    cc -o main \
+        main.c \
+        util.c <<< \
+        "x"
+    $flags = [
+        "-O2",  # 优化
+        # 警告
+        "-Wall",
+        [1,
+            2]]
+    $v = len(
+        $flags,  # 参数
+    ) + $f(1,
+        2)
+"#;
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_command_args() {
+        let source = "message (none) (true) none true\nmessage (len([1])) len([1]) (len([1]))[0] ($a)\n";
+        let expected = "message (none) (true) none true\nmessage (len([1])) len([1]) (len([1])[0]) $a\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_parens() {
+        // 五种运算符任意组合成的三个运算符的表达式, 格式化之后重新解析都得到相同的语法树
+        let ops = [OperatorData::Eq, OperatorData::Add, OperatorData::Sub, OperatorData::Mul, OperatorData::Div];
+        let name = |i: usize| Node::from(NodeKind::Name(format!("v{}", i)));
+        let expr = |l: Node, op: &OperatorData, r: Node| Node::create_expr(l, op.clone(), r);
+        let module = Node::from(NodeKind::Module { body: vec![], trivia: vec![] });
+        let formatter = Formatter::new("", &module);
+        for a in &ops {
+            for b in &ops {
+                for c in &ops {
+                    let trees = [
+                        expr(expr(expr(name(0), a, name(1)), b, name(2)), c, name(3)),
+                        expr(expr(name(0), a, expr(name(1), b, name(2))), c, name(3)),
+                        expr(expr(name(0), a, name(1)), b, expr(name(2), c, name(3))),
+                        expr(name(0), a, expr(expr(name(1), b, name(2)), c, name(3))),
+                        expr(name(0), a, expr(name(1), b, expr(name(2), c, name(3)))),
+                    ];
+                    for tree in trees {
+                        let text = formatter.expr(&tree);
+                        assert_eq!(parse::parse_expr(&text, 0).unwrap().without_span(), tree, "{}", text);
+                    }
+                }
+            }
+        }
+        let tree = expr(name(0), &OperatorData::Add, expr(name(1), &OperatorData::Mul, expr(name(2), &OperatorData::Sub, name(3))));
+        assert_eq!(formatter.expr(&tree), "$v0 + $v1 * ($v2 - $v3)");
+    }
+}
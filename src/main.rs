@@ -2,36 +2,79 @@ mod parse;
 mod rt;
 mod dt;
 mod ast;
+mod builtin;
+mod format;
 
 use clap::Parser;
-use crate::dt::{ErrorKind, ValueObject, WrapValueObject};
-
-fn doit(f: &str, target: &str) {
-    let code = std::fs::read_to_string(f).expect(format!("open file({}) failed", f).as_str());
+use crate::dt::Value;
 
+fn doit(f: &str, args: &Args) {
     let mut space = rt::RunSpace::default();
+    space.set_dry_run(args.dry_run);
+    space.set_tracing(args.trace);
     for (k, v) in std::env::vars() {
-        space.set(k.as_str(), WrapValueObject::from_box(Box::new(v)));
+        space.set(k.as_str(), Value::from(v));
     }
     if let Ok(v) = std::env::current_dir() {
         if let Some(v) = v.to_str() {
-            space.set("current_dir", WrapValueObject::from_box(Box::new(v.to_string())));
+            space.set("current_dir", Value::from(v.to_string()));
         }
     }
     if let Ok(v) = std::env::current_exe() {
         if let Some(v) = v.to_str() {
-            space.set("current_exe", WrapValueObject::from_box(Box::new(v.to_string())));
+            space.set("current_exe", Value::from(v.to_string()));
+        }
+    }
+    if let Err(e) = rt::exec_file(std::path::Path::new(f), &mut space) {
+        eprintln!("{}", e.render());
+        std::process::exit(1);
+    }
+    if args.parallel {
+        if let Err(e) = rt::exec_targets_parallel(&space, &args.targets) {
+            eprintln!("{}", e.render());
+            std::process::exit(1);
         }
+        return;
     }
-    if let Err(e) = rt::exec_code(code.as_str(), &mut space) {
-        let e = <ErrorKind as ValueObject>::to_str(&e);
-        let e = e.unwrap();
-        panic!("exec error: {}", e);
+    if let Err(e) = rt::exec_targets(&mut space, &args.targets) {
+        eprintln!("{}", e.render());
+        std::process::exit(1);
+    }
+}
+
+/// 格式化代码文件, check为true时只检查不修改
+fn fmt(files: &[String], check: bool) {
+    let mut failed = false;
+    for f in files {
+        let code = match std::fs::read_to_string(f) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("error: open file({}) failed: {}", f, e);
+                failed = true;
+                continue;
+            }
+        };
+        let output = match format::format_source(code.as_str()) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("{}", e.in_file(f).render());
+                failed = true;
+                continue;
+            }
+        };
+        if output == code {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", f);
+            failed = true;
+        } else if let Err(e) = std::fs::write(f, output) {
+            eprintln!("error: write file({}) failed: {}", f, e);
+            failed = true;
+        }
     }
-    if let Err(e) = rt::exec_target(&mut space, target) {
-        let e = <ErrorKind as ValueObject>::to_str(&e);
-        let e = e.unwrap();
-        panic!("build target {} error: {}", target, e);
+    if failed {
+        std::process::exit(1);
     }
 }
 
@@ -39,19 +82,44 @@ fn doit(f: &str, target: &str) {
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// 构建目标
-    target: String,
+    /// 构建目标, 加上--fmt时是要格式化的代码文件
+    #[arg(required_unless_present = "fmt")]
+    targets: Vec<String>,
+
+    /// 每个目标在单独的线程中并行构建
+    #[arg(short = 'j', long)]
+    parallel: bool,
+
+    /// 只显示要执行的命令和它们在代码中的位置, 不真正执行
+    #[arg(short = 'n', long)]
+    dry_run: bool,
+
+    /// 在标准错误中输出执行的target和命令
+    #[arg(long)]
+    trace: bool,
 
     /// 代码文件
     #[arg(short, long)]
     file: Option<String>,
+
+    /// 按照统一的风格格式化代码文件, 没有指定文件时格式化-f指定的文件
+    #[arg(long, conflicts_with_all = ["parallel", "dry_run", "trace"])]
+    fmt: bool,
+
+    /// 和--fmt一起使用, 只检查不修改, 有文件需要格式化时以非零状态退出
+    #[arg(long, requires = "fmt")]
+    check: bool,
 }
 
 fn main() {
     let args: Args = Args::parse();
 
-    doit(
-        args.file.unwrap_or("./main.tentacle".to_string()).as_str(),
-        args.target.as_str(),
-    )
+    let file = args.file.clone().unwrap_or("./main.tentacle".to_string());
+    if args.fmt {
+        let files = if args.targets.is_empty() { vec![file] } else { args.targets.clone() };
+        fmt(&files, args.check);
+        return;
+    }
+
+    doit(file.as_str(), &args)
 }
\ No newline at end of file
@@ -1,35 +1,185 @@
-use std::cell::{Cell};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use nom::{
-    IResult,
+    IResult, Slice,
     branch::{alt},
-    character::complete::{digit1, char, space0},
-    bytes::complete::{tag, take_while1},
-    combinator::{not, fail},
-    multi::{many0, many0_count},
-    sequence::{pair, delimited, separated_pair, terminated},
+    character::complete::{char, one_of, space0, space1, line_ending, not_line_ending},
+    bytes::complete::{tag, tag_no_case, take_while, take_while1, take_while_m_n},
+    combinator::{not, fail, cut, peek, value, map_opt, recognize, eof},
+    multi::{many0, many0_count, separated_list0},
+    sequence::{pair, tuple, delimited, terminated, preceded},
 };
 use nom::combinator::opt;
+use nom_locate::LocatedSpan;
 
-use crate::dt::{TResult, ErrorKind};
-use crate::ast::{Node, ValueData, OperatorData};
+use crate::dt::{TResult, ErrorKind, Location};
+use crate::ast;
+use crate::ast::{Node, NodeKind, ValueData, OperatorData, Comment};
 
+/// 附带源文件编号的输入
+pub type Span<'a> = LocatedSpan<&'a str, usize>;
+
+pub type PResult<'a, O> = IResult<Span<'a>, O, ParseError<'a>>;
+
+/// 解析错误, 记录出错位置和期望出现的内容
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError<'a> {
+    pub input: Span<'a>,
+    pub code: nom::error::ErrorKind,
+    pub expected: Option<&'static str>,
+}
+
+impl<'a> ParseError<'a> {
+    pub fn new(input: Span<'a>, code: nom::error::ErrorKind) -> Self {
+        Self { input, code, expected: None }
+    }
+    pub fn offset(&self) -> usize {
+        self.input.location_offset()
+    }
+    pub fn into_syntax_error(self) -> SyntaxError {
+        if self.code == nom::error::ErrorKind::TooLarge {
+            let literal = Parser::parse_number_text(self.input).map_or(*self.input.fragment(), |(_, v)| *v.fragment());
+            return SyntaxError::Invalid {
+                offset: self.offset(),
+                width: literal.len(),
+                message: format!("number literal out of range: {}", literal),
+            };
+        }
+        match self.expected {
+            Some(expected) => SyntaxError::Expected(self.offset(), expected),
+            None => SyntaxError::Invalid { offset: self.offset(), width: 1, message: "invalid syntax".to_string() },
+        }
+    }
+}
+
+/// 解析时收集的语法错误, 出错的语句会被跳过以便继续检查后面的代码
+#[derive(Debug, PartialEq, Clone)]
+pub enum SyntaxError {
+    /// 缺少某个内容, 位置指向前一个内容的末尾
+    Expected(usize, &'static str),
+    Invalid {
+        offset: usize,
+        width: usize,
+        message: String,
+    },
+}
+
+impl SyntaxError {
+    pub fn offset(&self) -> usize {
+        match self {
+            SyntaxError::Expected(offset, _) | SyntaxError::Invalid { offset, .. } => *offset,
+        }
+    }
+    pub fn to_error(&self, source: &str) -> ErrorKind {
+        match self {
+            SyntaxError::Expected(offset, expected) => {
+                let offset = source[..*offset].trim_end().len();
+                ErrorKind::Syntax(format!("expected {}", expected)).at(Location::from_offset(source, offset, 1))
+            }
+            SyntaxError::Invalid { offset, width, message } => {
+                ErrorKind::Syntax(message.clone()).at(Location::from_offset(source, *offset, *width))
+            }
+        }
+    }
+}
+
+impl<'a> nom::error::ParseError<Span<'a>> for ParseError<'a> {
+    fn from_error_kind(input: Span<'a>, kind: nom::error::ErrorKind) -> Self {
+        Self::new(input, kind)
+    }
+    fn append(_input: Span<'a>, _kind: nom::error::ErrorKind, other: Self) -> Self {
+        other
+    }
+    /// `alt`的所有分支都失败时, 保留解析得最远的那个错误
+    fn or(self, other: Self) -> Self {
+        if other.offset() > self.offset() || (other.offset() == self.offset() && self.expected.is_none()) {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+
+/// 跟在值后面的下标或切片
+#[derive(Debug, PartialEq, Clone)]
+pub enum Subscript {
+    Index(Node),
+    Slice(Option<Node>, Option<Node>),
+}
+
+impl Subscript {
+    /// span是下标的位置, 到`]`为止
+    pub fn apply(self, value: Node, span: ast::Span) -> Node {
+        let span = value.span.to(span);
+        let kind = match self {
+            Subscript::Index(index) => NodeKind::Index {
+                value: Box::new(value),
+                index: Box::new(index),
+            },
+            Subscript::Slice(start, end) => NodeKind::Slice {
+                value: Box::new(value),
+                start: start.map(Box::new),
+                end: end.map(Box::new),
+            },
+        };
+        Node::new(kind, span)
+    }
+}
+
+/// 缩进的单位, 同一个文件中只能使用一种
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IndentUnit {
+    Tab,
+    Spaces(usize),
+}
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Parser {
-    pub(crate) this_line: Cell<usize>,
     pub(crate) indentation: Cell<usize>,
+    /// 解析过程中遇到的注释, 以注释在源代码中的位置为键, 回溯时重复记录也不会出错
+    pub(crate) comments: RefCell<BTreeMap<usize, String>>,
+    /// 已经跳过的出错语句
+    pub(crate) errors: RefCell<Vec<SyntaxError>>,
+    /// 文件的缩进单位, 由第一个有缩进的行决定
+    pub(crate) indent_unit: Cell<Option<IndentUnit>>,
 }
 
 
 impl<'a> Parser {
-    pub fn next_to(&self, s: usize) {
-        self.this_line.set(self.this_line.get() + s);
-    }
-    pub fn next_line(&self) {
-        self.next_to(1);
+    /// 计算行首的缩进层数, 第一次遇到缩进时确定整个文件的缩进单位
+    pub fn measure_indentation(&self, input: Span<'a>) -> Result<(Span<'a>, usize), SyntaxError> {
+        let (rest, depth, unit) = self.peek_indentation(input)?;
+        self.indent_unit.set(unit);
+        Ok((rest, depth))
     }
-    pub fn this_line(&self) -> usize {
-        self.this_line.get()
+    /// 计算缩进层数但不确定文件的缩进单位, 同时返回这一行所用的缩进单位
+    pub fn peek_indentation(&self, input: Span<'a>) -> Result<(Span<'a>, usize, Option<IndentUnit>), SyntaxError> {
+        let (rest, space) = space0::<_, ParseError>(input).unwrap_or((input, input.slice(..0)));
+        let space = *space.fragment();
+        if space.is_empty() {
+            return Ok((rest, 0, self.indent_unit.get()));
+        }
+        let error = |message: String| SyntaxError::Invalid {
+            offset: input.location_offset(),
+            width: space.len(),
+            message,
+        };
+        let tab = space.starts_with('\t');
+        if space.contains('\t') && space.contains(' ') {
+            return Err(error("inconsistent use of tabs and spaces in indentation".to_string()));
+        }
+        let unit = self.indent_unit.get().unwrap_or(if tab { IndentUnit::Tab } else { IndentUnit::Spaces(space.len()) });
+        match (unit, tab) {
+            (IndentUnit::Tab, true) => Ok((rest, space.len(), Some(unit))),
+            (IndentUnit::Spaces(width), false) if space.len() % width == 0 => Ok((rest, space.len() / width, Some(unit))),
+            (IndentUnit::Spaces(width), false) => Err(error(format!(
+                "indentation of {} spaces is not a multiple of {}, the indentation width of this file",
+                space.len(), width,
+            ))),
+            (IndentUnit::Tab, false) => Err(error("indented with spaces but this file is indented with tabs".to_string())),
+            (IndentUnit::Spaces(_), true) => Err(error("indented with tabs but this file is indented with spaces".to_string())),
+        }
     }
     pub fn set_indentation(&self, s: usize) {
         self.indentation.set(s);
@@ -38,27 +188,96 @@ impl<'a> Parser {
         self.indentation.get()
     }
 
+    /// start和end之间的代码在源文件中的位置, 不包括末尾的空白
+    pub fn span(start: Span<'a>, end: Span<'a>) -> ast::Span {
+        let text = &start.fragment()[..end.location_offset() - start.location_offset()];
+        ast::Span {
+            file: start.extra,
+            start: start.location_offset(),
+            end: start.location_offset() + text.trim_end().len(),
+            line: start.location_line() as usize,
+            column: start.get_utf8_column(),
+        }
+    }
 
-    pub fn parse_alphanumeric_underscore(input: &'a str) -> IResult<&'a str, &'a str> {
-        take_while1(|c: char| {
-            match c {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
-                _ => false,
-            }
-        })(input)
+    /// 语句块的位置从开头一行到最后一条语句
+    pub fn block_span(header: ast::Span, body: &[Node]) -> ast::Span {
+        body.last().map_or(header, |i| header.to(i.span))
+    }
+
+    /// 关键字后面不能紧跟着名字中的字符, 避免把`format`当作`for`, 把`if-up`当作`if`
+    pub fn keyword(word: &'static str) -> impl Fn(Span<'a>) -> PResult<'a, Span<'a>> {
+        move |input: Span<'a>| {
+            terminated(tag(word), not(alt((
+                |input: Span<'a>| { Parser::parse_identifier(input) },
+                recognize(pair(one_of("-."), |input: Span<'a>| { Parser::parse_identifier(input) })),
+            ))))(input)
+        }
+    }
+
+    /// 解析失败并且没有前进时, 报告期望出现的内容
+    pub fn expect<O>(
+        expected: &'static str,
+        mut parser: impl FnMut(Span<'a>) -> PResult<'a, O>,
+    ) -> impl FnMut(Span<'a>) -> PResult<'a, O> {
+        move |input: Span<'a>| {
+            // 只越过了空白也不算前进
+            let start = input.location_offset() + input.len() - input.trim_start_matches([' ', '\t']).len();
+            parser(input).map_err(|e| e.map(|e| {
+                if e.offset() > start {
+                    e
+                } else {
+                    ParseError { input, code: nom::error::ErrorKind::Tag, expected: Some(expected) }
+                }
+            }))
+        }
+    }
+
+    /// 变量名, 目标名和命令名的组成部分, 支持`中文`等Unicode字符
+    pub fn parse_identifier(input: Span<'a>) -> PResult<'a, Span<'a>> {
+        take_while1(unicode_ident::is_xid_continue)(input)
+    }
+    /// 命令名中间可以有`-`和`.`, 比如`cargo-fmt`和`python3.11`
+    pub fn parse_command_name(input: Span<'a>) -> PResult<'a, Span<'a>> {
+        recognize(pair(
+            |input: Span<'a>| { Parser::parse_identifier(input) },
+            many0(pair(one_of("-."), |input: Span<'a>| { Parser::parse_identifier(input) })),
+        ))(input)
+    }
+    /// 空格以及行末的`\`续行
+    pub fn parse_space0(input: Span<'a>) -> PResult<'a, Span<'a>> {
+        recognize(many0_count(alt((
+            space1,
+            recognize(pair(char('\\'), line_ending)),
+        ))))(input)
+    }
+    /// 括号中除了空格和续行之外还可以直接换行, 换行之前可以有注释
+    pub fn parse_multispace0(ctx: &Parser, input: Span<'a>) -> PResult<'a, Span<'a>> {
+        recognize(many0_count(alt((
+            space1,
+            line_ending,
+            recognize(pair(char('\\'), line_ending)),
+            recognize(|input: Span<'a>| { Parser::parse_comment(ctx, input) }),
+        ))))(input)
     }
-    pub fn parse_name(input: &'a str) -> IResult<&'a str, &'a str> {
+
+    pub fn parse_name(input: Span<'a>) -> PResult<'a, Span<'a>> {
         let (input, _) = tag("$")(input)?;
-        let (input, value) = Parser::parse_alphanumeric_underscore(input)?;
+        // `$ns.name`用于访问导入的模块中的变量
+        let (input, value) = recognize(pair(
+            |input: Span<'a>| { Parser::parse_identifier(input) },
+            many0(pair(char('.'), |input: Span<'a>| { Parser::parse_identifier(input) })),
+        ))(input)?;
         Ok((input, value))
     }
 
-    pub fn parse_name_node(input: &'a str) -> IResult<&'a str, Node> {
+    pub fn parse_name_node(input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
         let (input, value) = Parser::parse_name(input)?;
-        Ok((input, Node::Name(value.to_string())))
+        Ok((input, Node::new(NodeKind::Name(value.to_string()), Parser::span(start, input))))
     }
 
-    pub fn parse_operator_data(input: &str) -> IResult<&str, OperatorData> {
+    pub fn parse_operator_data(input: Span<'a>) -> PResult<'a, OperatorData> {
         let (input, value) = alt((
             tag("=="),
             tag("!="),
@@ -67,7 +286,7 @@ impl<'a> Parser {
             tag("*"),
             tag("/"),
         ))(input)?;
-        let value = match value {
+        let value = match *value.fragment() {
             "==" => OperatorData::Eq,
             "!=" => OperatorData::NotEq,
             "+" => OperatorData::Add,
@@ -80,125 +299,346 @@ impl<'a> Parser {
     }
 
 
-    pub fn parse_value(input: &'a str) -> IResult<&str, Node> {
+    /// 某个进制的数字, 第一个字符之后可以用`_`分隔
+    pub fn parse_digits(radix: u32) -> impl Fn(Span<'a>) -> PResult<'a, Span<'a>> {
+        move |input: Span<'a>| {
+            recognize(pair(
+                take_while1(|c: char| c.is_digit(radix)),
+                take_while(|c: char| c.is_digit(radix) || c == '_'),
+            ))(input)
+        }
+    }
+
+    /// 识别数字字面量, 不做转换
+    pub fn parse_number_text(input: Span<'a>) -> PResult<'a, Span<'a>> {
+        recognize(pair(
+            opt(one_of("+-")),
+            alt((
+                recognize(pair(tag_no_case("0x"), Parser::parse_digits(16))),
+                recognize(pair(tag_no_case("0o"), Parser::parse_digits(8))),
+                recognize(pair(tag_no_case("0b"), Parser::parse_digits(2))),
+                recognize(pair(
+                    alt((
+                        recognize(pair(Parser::parse_digits(10), opt(pair(char('.'), Parser::parse_digits(10))))),
+                        recognize(pair(char('.'), Parser::parse_digits(10))),
+                    )),
+                    opt(tuple((one_of("eE"), opt(one_of("+-")), Parser::parse_digits(10)))),
+                )),
+            )),
+        ))(input)
+    }
+
+    pub fn parse_number(input: Span<'a>) -> PResult<'a, ValueData> {
+        let (rest, text) = Parser::parse_number_text(input)?;
+        // 超出范围时直接报错, 避免被当作命令参数重新解析
+        let overflow = || nom::Err::Failure(ParseError::new(input, nom::error::ErrorKind::TooLarge));
+
+        let text = text.replace('_', "");
+        let (sign, body) = match text.strip_prefix('-') {
+            Some(body) => ("-", body),
+            None => ("", text.strip_prefix('+').unwrap_or(&text)),
+        };
+        let radix = match body.get(..2).map(|i| i.to_ascii_lowercase()).as_deref() {
+            Some("0x") => 16,
+            Some("0o") => 8,
+            Some("0b") => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            let v = i64::from_str_radix(&format!("{}{}", sign, &body[2..]), radix).map_err(|_| overflow())?;
+            return Ok((rest, ValueData::Int(v)));
+        }
+        if body.contains(['.', 'e', 'E']) {
+            let v = format!("{}{}", sign, body).parse::<f64>().map_err(|_| overflow())?;
+            if !v.is_finite() {
+                return Err(overflow());
+            }
+            return Ok((rest, ValueData::Float(v)));
+        }
+        let v = format!("{}{}", sign, body).parse::<i64>().map_err(|_| overflow())?;
+        Ok((rest, ValueData::Int(v)))
+    }
+
+    pub fn parse_value(input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
         let (input, value) = alt((
-            |input: &'a str| {
-                let mut symbol: f64 = 1.0;
-                let (input, test_symbol) = opt(tag("+"))(input)?;
-                if let Some(_) = test_symbol {
-                    symbol = 1.0;
+            |input: Span<'a>| { Parser::parse_number(input) },
+            |input: Span<'a>| {
+                let (input, literal) = alt((
+                    value(ValueData::Bool(true), tag("true")),
+                    value(ValueData::Bool(false), tag("false")),
+                    value(ValueData::None, tag("none")),
+                ))(input)?;
+                // 避免把`trueish`这样的单词拆开
+                let (input, _) = not(|input: Span<'a>| { Parser::parse_identifier(input) })(input)?;
+                Ok((input, literal))
+            },
+            |input: Span<'a>| {
+                let (input, value) = Parser::parse_multiline_string(input)?;
+                Ok((input, ValueData::String(value)))
+            },
+            |input: Span<'a>| {
+                let (input, value) = Parser::parse_string(input)?;
+                Ok((input, ValueData::String(value)))
+            },
+        ))(input)?;
+        Ok((input, Node::new(NodeKind::Value(value), Parser::span(start, input))))
+    }
+
+    pub fn parse_escape(input: Span<'a>) -> PResult<'a, char> {
+        // 转义字符出错时直接报错, 避免被当作命令参数重新解析
+        cut(alt((
+            value('\n', char('n')),
+            value('\r', char('r')),
+            value('\t', char('t')),
+            value('\0', char('0')),
+            value('\\', char('\\')),
+            value('"', char('"')),
+            value('\'', char('\'')),
+            map_opt(
+                preceded(
+                    char('u'),
+                    delimited(
+                        char('{'),
+                        take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
+                        char('}'),
+                    ),
+                ),
+                |v: Span<'a>| u32::from_str_radix(v.fragment(), 16).ok().and_then(char::from_u32),
+            ),
+        )))(input)
+    }
+
+    pub fn parse_string(input: Span<'a>) -> PResult<'a, String> {
+        let (mut input, _) = char('"')(input)?;
+        let mut value = String::new();
+        loop {
+            match input.fragment().chars().next() {
+                Some('"') => {
+                    return Ok((input.slice(1..), value));
                 }
-                let (input, test_symbol) = opt(tag("-"))(input)?;
-                if let Some(_) = test_symbol {
-                    symbol = -1.0;
+                Some('\\') => {
+                    let (next_input, c) = Parser::parse_escape(input.slice(1..))?;
+                    value.push(c);
+                    input = next_input;
                 }
-
-                let (input, left_value) = digit1(input)?;
-                let (input, _) = char('.')(input)?;
-                let (input, right_value) = digit1(input)?;
-                let value = format!("{}.{}", left_value, right_value);
-                Ok((input, ValueData::Float(value.parse::<f64>().unwrap() * symbol)))
-            },
-            |input: &'a str| {
-                let mut symbol: i64 = 1;
-                let (input, test_symbol) = opt(tag("+"))(input)?;
-                if let Some(_) = test_symbol {
-                    symbol = 1;
+                Some(c) => {
+                    value.push(c);
+                    input = input.slice(c.len_utf8()..);
                 }
-                let (input, test_symbol) = opt(tag("-"))(input)?;
-                if let Some(_) = test_symbol {
-                    symbol = -1;
+                None => {
+                    return Err(nom::Err::Error(ParseError::new(input, nom::error::ErrorKind::Char)));
                 }
+            }
+        }
+    }
 
-                let (input, value) = digit1(input)?;
-                Ok((input, ValueData::Int(value.parse::<i64>().unwrap() * symbol)))
-            },
-            |input: &'a str| {
-                let is_escape = Cell::new(false);
-                let (input, _) = tag("\"")(input)?;
-                let (input, value) = take_while1(|c: char| {
-                    if is_escape.get() {
-                        is_escape.set(false);
-                        return true;
-                    }
-                    if c == '"' {
-                        false
-                    } else {
-                        if c == '\\' {
-                            is_escape.set(true);
+    /// 处理字符串中的转义字符, 转义出错时返回None
+    pub fn unescape(input: &str) -> Option<String> {
+        let mut input = input;
+        let mut value = String::new();
+        while let Some(i) = input.find('\\') {
+            value.push_str(&input[..i]);
+            let (next_input, c) = Parser::parse_escape(Span::new_extra(&input[i + 1..], 0)).ok()?;
+            value.push(c);
+            input = &input[input.len() - next_input.len()..];
+        }
+        value.push_str(input);
+        Some(value)
+    }
+
+    /// 去掉多行字符串中所有非空行共同的缩进
+    pub fn dedent(value: &str) -> String {
+        // 开头的`"""`后紧跟的换行和结尾`"""`前只有空白的行不算作字符串内容
+        let value = value.strip_prefix("\r\n").or_else(|| value.strip_prefix('\n')).unwrap_or(value);
+        let value = match value.rfind('\n') {
+            Some(i) if value[i + 1..].trim().is_empty() => &value[..i + 1],
+            _ => value,
+        };
+        let indentation = value.lines()
+            .filter(|i| !i.trim().is_empty())
+            .map(|i| i.len() - i.trim_start_matches([' ', '\t']).len())
+            .min()
+            .unwrap_or(0);
+        let mut lines = Vec::new();
+        for i in value.split('\n') {
+            // 只有空白的行可能比共同的缩进短, 也可能以非ASCII的空白开头
+            let prefix = i.get(..indentation).filter(|p| p.bytes().all(|b| b == b' ' || b == b'\t'));
+            lines.push(if prefix.is_some() { &i[indentation..] } else { i.trim_start_matches([' ', '\t']) });
+        }
+        lines.join("\n")
+    }
+
+    pub fn parse_multiline_string(input: Span<'a>) -> PResult<'a, String> {
+        let (input, _) = tag("\"\"\"")(input)?;
+        let start = input;
+        let text = *input.fragment();
+        let mut chars = text.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => { chars.next(); }
+                '"' if text[i..].starts_with("\"\"\"") => {
+                    let value = Parser::dedent(&text[..i]);
+                    // 缩进处理之后的内容不是输入的一部分, 转义出错时指向字符串开头
+                    let value = match Parser::unescape(value.as_str()) {
+                        Some(value) => value,
+                        None => {
+                            return Err(nom::Err::Failure(ParseError::new(start, nom::error::ErrorKind::Escaped)));
                         }
-                        true
-                    }
-                })(input)?;
-                let (input, _) = tag("\"")(input)?;
-                Ok((input, ValueData::String(value.to_string())))
-            },
-        ))(input)?;
-        Ok((input, Node::Value(value)))
+                    };
+                    return Ok((input.slice(i + 3..), value));
+                }
+                _ => {}
+            }
+        }
+        Err(nom::Err::Error(ParseError::new(input, nom::error::ErrorKind::Char)))
     }
 
-    pub fn parse_list(input: &'a str) -> IResult<&str, Node> {
+    pub fn parse_list(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
         let (input, _) = tag("[")(input)?;
+        let multispace0 = |input: Span<'a>| { Parser::parse_multispace0(ctx, input) };
 
-        let (input, start_node) = opt(|input: &'a str| { Parser::parse_expr(input) })(input)?;
+        let (input, _) = multispace0(input)?;
+        let (input, start_node) = opt(|input: Span<'a>| { Parser::parse_expr(ctx, input) })(input)?;
         match start_node {
             Some(start_node) => {
+                if let (input, Some((item_var_name, source_exp, condition))) = opt(|input: Span<'a>| {
+                    Parser::parse_comprehension(ctx, input)
+                })(input)? {
+                    let (input, _) = preceded(multispace0, Parser::expect("']' to close the list", tag("]")))(input)?;
+                    let kind = NodeKind::ListComp {
+                        element: Box::new(start_node),
+                        item_var_name: item_var_name.to_string(),
+                        source_exp: Box::new(source_exp),
+                        condition: condition.map(Box::new),
+                    };
+                    return Ok((input, Node::new(kind, Parser::span(start, input))));
+                }
                 let mut list_childs = vec![start_node];
                 let (input, nodes) = many0(pair(
-                    delimited(space0, tag(","), space0),
-                    |input: &'a str| { Parser::parse_expr(input) },
+                    delimited(multispace0, tag(","), multispace0),
+                    |input: Span<'a>| { Parser::parse_expr(ctx, input) },
                 ))(input)?;
                 for (_, i) in nodes {
                     list_childs.push(i);
                 }
-                let (input, _) = delimited(space0, opt(tag(",")), space0)(input)?;
-                let (input, _) = tag("]")(input)?;
-                Ok((input, Node::List(list_childs)))
+                let (input, _) = delimited(multispace0, opt(tag(",")), multispace0)(input)?;
+                let (input, _) = Parser::expect("']' to close the list", tag("]"))(input)?;
+                Ok((input, Node::new(NodeKind::List(list_childs), Parser::span(start, input))))
             }
             None => {
-                let (input, _) = tag("]")(input)?;
-                Ok((input, Node::List(Vec::new())))
+                let (input, _) = Parser::expect("']' to close the list", tag("]"))(input)?;
+                Ok((input, Node::new(NodeKind::List(Vec::new()), Parser::span(start, input))))
             }
         }
     }
 
-    pub fn parse_a_have_value_node(input: &'a str) -> IResult<&'a str, Node> {
-        let (input, value) = delimited(
-            space0,
-            alt((
-                |input: &'a str| { Parser::parse_value(input) },
-                |input: &'a str| { Parser::parse_name_node(input) },
-                |input: &'a str| {
-                    delimited(
-                        char('('),
-                        |input: &'a str| { Parser::parse_expr(input) },
-                        char(')'),
-                    )(input)
-                },
-                |input: &'a str| { Parser::parse_list(input) },
-            )),
-            space0,
+    /// 列表推导式中`for $item in source if condition`的部分
+    pub fn parse_comprehension(ctx: &Parser, input: Span<'a>) -> PResult<'a, (Span<'a>, Node, Option<Node>)> {
+        let multispace0 = |input: Span<'a>| { Parser::parse_multispace0(ctx, input) };
+        let (input, _) = delimited(multispace0, tag("for"), multispace0)(input)?;
+        let (input, item_var_name) = Parser::parse_name(input)?;
+        let (input, _) = delimited(multispace0, tag("in"), multispace0)(input)?;
+        let (input, source_exp) = Parser::parse_expr(ctx, input)?;
+        let (input, condition) = opt(preceded(
+            delimited(multispace0, tag("if"), multispace0),
+            |input: Span<'a>| { Parser::parse_expr(ctx, input) },
+        ))(input)?;
+        Ok((input, (item_var_name, source_exp, condition)))
+    }
+
+    pub fn parse_args(ctx: &Parser, input: Span<'a>) -> PResult<'a, Vec<Node>> {
+        let multispace0 = |input: Span<'a>| { Parser::parse_multispace0(ctx, input) };
+        let (input, _) = pair(tag("("), multispace0)(input)?;
+        let (input, args) = separated_list0(
+            delimited(multispace0, tag(","), multispace0),
+            |input: Span<'a>| { Parser::parse_expr(ctx, input) },
+        )(input)?;
+        // 多行参数列表允许以逗号结尾
+        let (input, _) = opt(pair(multispace0, tag(",")))(input)?;
+        let (input, _) = delimited(
+            multispace0,
+            Parser::expect("')' to close the argument list", tag(")")),
+            |input: Span<'a>| { Parser::parse_space0(input) },
         )(input)?;
+        Ok((input, args))
+    }
+
+    pub fn parse_call(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, name) = Parser::parse_name(input)?;
+        // `$name(`之后一定是函数调用
+        let (input, _) = peek(char('('))(input)?;
+        let (input, args) = cut(|input: Span<'a>| { Parser::parse_args(ctx, input) })(input)?;
+        Ok((input, Node::new(NodeKind::Call { name: name.to_string(), args }, Parser::span(start, input))))
+    }
+
+    pub fn parse_native_call(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, name) = Parser::parse_identifier(input)?;
+        let (input, args) = Parser::parse_args(ctx, input)?;
+        Ok((input, Node::new(NodeKind::NativeCall { name: name.to_string(), args }, Parser::span(start, input))))
+    }
+
+    pub fn parse_subscript(ctx: &Parser, input: Span<'a>) -> PResult<'a, (Subscript, ast::Span)> {
+        let subscript_start = input;
+        let multispace0 = |input: Span<'a>| { Parser::parse_multispace0(ctx, input) };
+        let (input, _) = pair(tag("["), multispace0)(input)?;
+        let (input, start) = opt(|input: Span<'a>| { Parser::parse_expr(ctx, input) })(input)?;
+        let (input, colon) = opt(terminated(tag(":"), multispace0))(input)?;
+        let (input, subscript) = match (start, colon) {
+            (start, Some(_)) => {
+                let (input, end) = opt(|input: Span<'a>| { Parser::parse_expr(ctx, input) })(input)?;
+                (input, Subscript::Slice(start, end))
+            }
+            (Some(index), None) => (input, Subscript::Index(index)),
+            (None, None) => { return fail(input); }
+        };
+        let (input, _) = preceded(multispace0, Parser::expect("']' to close the subscript", tag("]")))(input)?;
+        Ok((input, (subscript, Parser::span(subscript_start, input))))
+    }
+
+    pub fn parse_a_have_value_node(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let (input, _) = Parser::parse_space0(input)?;
+        let (input, value) = alt((
+            |input: Span<'a>| { Parser::parse_value(input) },
+            |input: Span<'a>| { Parser::parse_call(ctx, input) },
+            |input: Span<'a>| { Parser::parse_native_call(ctx, input) },
+            |input: Span<'a>| { Parser::parse_name_node(input) },
+            |input: Span<'a>| {
+                delimited(
+                    pair(char('('), |input: Span<'a>| { Parser::parse_multispace0(ctx, input) }),
+                    |input: Span<'a>| { Parser::parse_expr(ctx, input) },
+                    pair(|input: Span<'a>| { Parser::parse_multispace0(ctx, input) }, Parser::expect("')'", char(')'))),
+                )(input)
+            },
+            |input: Span<'a>| { Parser::parse_list(ctx, input) },
+        ))(input)?;
+        // 下标和切片必须紧跟在值后面, 避免和命令参数中的列表混淆
+        let (input, subscripts) = many0(|input: Span<'a>| { Parser::parse_subscript(ctx, input) })(input)?;
+        let value = subscripts.into_iter().fold(value, |value, (i, span)| i.apply(value, span));
+        let (input, _) = Parser::parse_space0(input)?;
         Ok((input, value))
     }
 
-    pub fn parse_expr(input: &'a str) -> IResult<&'a str, Node> {
+    pub fn parse_expr(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
         // dbg!(format!("this is {:?}", input));
-        let (input, mut left_node) = Parser::parse_a_have_value_node(input)?;
+        let (input, mut left_node) = Parser::parse_a_have_value_node(ctx, input)?;
         // dbg!(format!("left_node is {:?}", left_node));
 
 
         let (input, mut right_vec) = many0(pair(
-            |input: &'a str| { Parser::parse_operator_data(input) },
-            |input: &'a str| { Parser::parse_a_have_value_node(input) },
+            |input: Span<'a>| { Parser::parse_operator_data(input) },
+            |input: Span<'a>| { Parser::parse_a_have_value_node(ctx, input) },
         ))(input)?;
 
         // dbg!(format!("right_vec is {:?}", right_vec));
 
-        while right_vec.len() > 0 {
+        while !right_vec.is_empty() {
             let (op, mut right_node) = right_vec.remove(0);
             // dbg!(format!("(op, right_node) is {:?} {:?}", op, right_node));
-            if right_vec.len() > 0 {
+            if !right_vec.is_empty() {
                 let (next_op, next_right_node) = right_vec.remove(0);
                 if next_op.get_priority() > op.get_priority() {
                     right_node = Node::create_expr(right_node, next_op, next_right_node);
@@ -213,126 +653,276 @@ impl<'a> Parser {
 
         Ok((input, left_node))
     }
-    pub fn parse_command(input: &'a str) -> IResult<&'a str, Node> {
-        let (input, command) = Parser::parse_alphanumeric_underscore(input)?;
+    pub fn parse_command(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, command) = Parser::parse_command_name(input)?;
+        let stdin_tag = "<<<";
         let (input, args) = many0(delimited(
-            space0,
+            |input: Span<'a>| { Parser::parse_space0(input) },
             alt((
-                |input: &'a str| { Parser::parse_a_have_value_node(input) },
-                |input: &'a str| {
-                    let (input, value) = take_while1(|c: char| {
-                        match c {
-                            '\t' | ' ' | '\r' | '\n' => false,
-                            _ => true,
-                        }
+                |input: Span<'a>| {
+                    // 参数中的`name(...)`是普通的单词, 不是内置函数调用
+                    let (input, _) = not(pair(|input: Span<'a>| { Parser::parse_identifier(input) }, char('(')))(input)?;
+                    // `true`, `false`和`none`也按单词原样传给命令
+                    let (input, _) = not(alt((
+                        Parser::keyword("true"),
+                        Parser::keyword("false"),
+                        Parser::keyword("none"),
+                    )))(input)?;
+                    // 数字后面不是单词边界时是普通的单词, 比如`1.2.3`和`2024-01-01`
+                    let (input, _) = not(pair(
+                        Parser::parse_number_text,
+                        not(alt((recognize(one_of(" \t\r\n\\")), eof, tag(stdin_tag)))),
+                    ))(input)?;
+                    Parser::parse_a_have_value_node(ctx, input)
+                },
+                |input: Span<'a>| {
+                    // `#`开头的是注释, 不是参数
+                    let (input, _) = not(char('#'))(input)?;
+                    let (input, _) = not(tag(stdin_tag))(input)?;
+                    let (rest, value) = take_while1(|c: char| {
+                        !matches!(c, '\t' | ' ' | '\r' | '\n')
                     })(input)?;
-                    Ok((input, Node::Value(ValueData::String(value.to_string()))))
+                    let kind = NodeKind::Value(ValueData::String(value.to_string()));
+                    Ok((rest, Node::new(kind, Parser::span(input, rest))))
                 },
             )),
-            space0,
+            |input: Span<'a>| { Parser::parse_space0(input) },
+        ))(input)?;
+        // `<<<`之后的值作为命令的标准输入
+        let (input, stdin) = opt(preceded(
+            delimited(space0, tag(stdin_tag), space0),
+            |input: Span<'a>| { Parser::parse_a_have_value_node(ctx, input) },
         ))(input)?;
-        Ok((input, Node::Command { command: command.to_string(), args }))
+        let kind = NodeKind::Command { command: command.to_string(), args, stdin: stdin.map(Box::new) };
+        Ok((input, Node::new(kind, Parser::span(start, input))))
     }
-    pub fn parse_set_attr(input: &'a str) -> IResult<&'a str, Node> {
-        let (input, node) = separated_pair(
-            |input: &'a str| { Parser::parse_name(input) },
-            delimited(space0, char('='), space0),
-            alt((
-                |input: &'a str| { Parser::parse_expr(input) },
-                |input: &'a str| { Parser::parse_command(input) },
-            )),
+    pub fn parse_set_attr(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, (name, mut subscripts)) = pair(
+            |input: Span<'a>| { Parser::parse_name(input) },
+            many0(|input: Span<'a>| { Parser::parse_subscript(ctx, input) }),
         )(input)?;
-        Ok((input, Node::SetAttr { name: node.0.to_string(), value: Box::from(node.1) }))
+        let (input, op) = delimited(space0, alt((tag("?="), tag("+="), tag("="))), space0)(input)?;
+        let (input, value) = Parser::expect("value after assignment", alt((
+            |input: Span<'a>| { Parser::parse_expr(ctx, input) },
+            |input: Span<'a>| { Parser::parse_command(ctx, input) },
+        )))(input)?;
+        let value = Box::from(value);
+        let span = Parser::span(start, input);
+        let name_node = Node::new(NodeKind::Name(name.to_string()), Parser::span(start, name.slice(name.len()..)));
+        let kind = match *op.fragment() {
+            "?=" if subscripts.is_empty() => NodeKind::SetDefault { name: name.to_string(), value },
+            "?=" => { return fail(input); }
+            "+=" => {
+                if let Some((Subscript::Slice(_, _), _)) = subscripts.last() {
+                    return fail(input);
+                }
+                let target = subscripts.into_iter().fold(name_node, |value, (i, span)| i.apply(value, span));
+                NodeKind::AddAssign { target: Box::new(target), value }
+            }
+            _ => match subscripts.pop() {
+                None => NodeKind::SetAttr { name: name.to_string(), value },
+                Some((Subscript::Index(index), _)) => {
+                    let target = subscripts.into_iter().fold(name_node, |value, (i, span)| i.apply(value, span));
+                    NodeKind::SetIndex { target: Box::new(target), index: Box::new(index), value }
+                }
+                // 不支持对切片赋值
+                Some((Subscript::Slice(_, _), _)) => { return fail(input); }
+            },
+        };
+        Ok((input, Node::new(kind, span)))
     }
-    pub fn parse_crlf_or_ending(ctx: &Parser, input: &'a str) -> IResult<&'a str, ()> {
-        let (input, _) = space0(input)?;
-        if input.len() > 0 {
-            let (input, _) = alt((tag("\n"), tag("\r\n")))(input)?;
-            ctx.next_line();
+    pub fn parse_declare(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, keyword) = terminated(alt((Parser::keyword("local"), Parser::keyword("global"))), space1)(input)?;
+        let (input, name) = cut(Parser::expect("variable name", |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+        let (input, value) = opt(preceded(
+            delimited(space0, char('='), space0),
+            cut(Parser::expect("value after '='", |input: Span<'a>| { Parser::parse_expr(ctx, input) })),
+        ))(input)?;
+        let span = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        let name = name.to_string();
+        let value = value.map(Box::new);
+        match *keyword.fragment() {
+            "local" => Ok((input, Node::new(NodeKind::Local { name, value }, span))),
+            _ => Ok((input, Node::new(NodeKind::Global { name, value }, span))),
+        }
+    }
+
+    pub fn parse_include(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = terminated(Parser::keyword("include"), space1)(input)?;
+        let (input, path) = cut(Parser::expect("path after 'include'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let span = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        Ok((input, Node::new(NodeKind::Include(Box::new(path)), span)))
+    }
+
+    pub fn parse_import(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = terminated(Parser::keyword("import"), space1)(input)?;
+        let (input, path) = cut(Parser::expect("path after 'import'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let (input, _) = cut(Parser::expect("'as' after import path", terminated(Parser::keyword("as"), space1)))(input)?;
+        let (input, name) = cut(Parser::expect("module name after 'as'", |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+        let span = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        Ok((input, Node::new(NodeKind::Import { path: Box::new(path), name: name.to_string() }, span)))
+    }
+
+    pub fn parse_comment(ctx: &Parser, input: Span<'a>) -> PResult<'a, ()> {
+        let offset = input.location_offset();
+        let (input, _) = char('#')(input)?;
+        let (input, text) = not_line_ending(input)?;
+        ctx.comments.borrow_mut().insert(offset, text.to_string());
+        Ok((input, ()))
+    }
+
+    /// 根据记录的注释位置计算出注释所在的行
+    pub fn collect_comments(ctx: &Parser, source: &str) -> Vec<Comment> {
+        ctx.comments.borrow().iter().map(|(offset, text)| {
+            let offset = *offset;
+            let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+            Comment {
+                line: source[..offset].matches('\n').count() + 1,
+                text: text.clone(),
+                trailing: !source[line_start..offset].trim().is_empty(),
+            }
+        }).collect()
+    }
+
+    pub fn parse_crlf_or_ending(ctx: &Parser, input: Span<'a>) -> PResult<'a, ()> {
+        let (input, _) = Parser::parse_space0(input)?;
+        let (input, _) = opt(|input: Span<'a>| { Parser::parse_comment(ctx, input) })(input)?;
+        if !input.is_empty() {
+            let (input, _) = Parser::expect("end of line", alt((tag("\n"), tag("\r\n"))))(input)?;
             Ok((input, ()))
         } else {
             Ok((input, ()))
         }
     }
 
-    pub fn parse_blank_line(ctx: &Parser, input: &'a str) -> IResult<&'a str, ()> {
-        // dbg!(format!("input {:#?}", input));
-        // let (input, _) = space0(input)?;
-        // let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
-        let (input, _) = pair(
-            many0(alt((tag(" "), tag("\t")))),
-            alt((tag("\n"), tag("\r\n"))),
-        )(input)?;
-        // dbg!(format!("output {:#?}", input));
-        // ctx.next_line();
+    pub fn parse_blank_line(ctx: &Parser, input: Span<'a>) -> PResult<'a, ()> {
+        // 只有注释的行也当作空行处理
+        let (input, _) = space0(input)?;
+        let (input, _) = opt(|input: Span<'a>| { Parser::parse_comment(ctx, input) })(input)?;
+        let (input, _) = line_ending(input)?;
         Ok((input, ()))
     }
 
 
-    pub fn parse_target_block(ctx: &Parser, input: &'a str) -> IResult<&'a str, Node> {
-        let (input, _) = delimited(space0, tag("target"), space0)(input)?;
-        let (input, name) = Parser::parse_name(input)?;
-        let (input, _) = delimited(space0, tag(":"), space0)(input)?;
+    pub fn parse_target_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = delimited(space0, Parser::keyword("target"), space0)(input)?;
+        let (input, name) = cut(Parser::expect("target name", |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+        let (input, _) = cut(Parser::expect("':' after target name", delimited(space0, tag(":"), space0)))(input)?;
         let (input, require_nodes) = many0(
             terminated(
-                |input: &'a str| {
+                |input: Span<'a>| {
                     let (input, value) = Parser::parse_name(input)?;
                     Ok((input, value.to_string()))
                 },
-                space0,
+                |input: Span<'a>| { Parser::parse_space0(input) },
             )
         )(input)?;
-        let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
 
-        let (input, body) = Parser::parse_block(ctx, input, ctx.get_indentation() + 1)?;
-        Ok((input, Node::Target {
+        let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
+        let span = Parser::block_span(header, &body);
+        let kind = NodeKind::Target {
             name: name.to_string(),
             require: require_nodes,
             body,
-        }))
+        };
+        Ok((input, Node::new(kind, span)))
     }
 
-    pub fn parse_if_block(ctx: &Parser, input: &'a str) -> IResult<&'a str, Node> {
+    pub fn parse_def_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = delimited(space0, Parser::keyword("def"), space0)(input)?;
+        let (input, name) = cut(Parser::expect("function name", |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+        let multispace0 = |input: Span<'a>| { Parser::parse_multispace0(ctx, input) };
+        let (input, _) = cut(Parser::expect("'(' after function name", tag("(")))(input)?;
+        let (input, params) = separated_list0(
+            tag(","),
+            delimited(multispace0, |input: Span<'a>| { Parser::parse_name(input) }, multispace0),
+        )(input)?;
+        let (input, _) = cut(Parser::expect("')' after function parameters", tag(")")))(input)?;
+        let (input, _) = cut(Parser::expect("':' after function parameters", delimited(space0, tag(":"), space0)))(input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+
+        let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
+        let span = Parser::block_span(header, &body);
+        let kind = NodeKind::Function {
+            name: name.to_string(),
+            params: params.into_iter().map(|i| i.to_string()).collect(),
+            body,
+        };
+        Ok((input, Node::new(kind, span)))
+    }
+
+    pub fn parse_return(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let (keyword_end, _) = Parser::keyword("return")(input)?;
+        let span = Parser::span(input, keyword_end);
+        let (input, value) = cut(alt((
+            |input: Span<'a>| {
+                let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
+                Ok((input, None))
+            },
+            |input: Span<'a>| {
+                let (input, _) = space1(input)?;
+                let (input, value) = Parser::parse_expr(ctx, input)?;
+                let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
+                Ok((input, Some(Box::new(value))))
+            },
+        )))(keyword_end)?;
+        let span = value.as_ref().map_or(span, |i| span.to(i.span));
+        Ok((input, Node::new(NodeKind::Return(value), span)))
+    }
+
+    pub fn parse_if_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
         let now_indentation = ctx.get_indentation();
         // dbg!(format!("parse_if_block if start {:?}", input));
         // if
-        let (input, _) = delimited(space0, tag("if"), space0)(input)?;
-        let (input, if_check_exp) = Parser::parse_expr(input)?;
-        let (input, _) = delimited(space0, tag(":"), space0)(input)?;
-        let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
+        let start = input;
+        let (input, _) = delimited(space0, Parser::keyword("if"), space0)(input)?;
+        let (input, if_check_exp) = cut(Parser::expect("condition after 'if'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let (input, _) = cut(Parser::expect("':' after condition", delimited(space0, tag(":"), space0)))(input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
 
-        let (input, if_node_body) = Parser::parse_block(ctx, input, ctx.get_indentation() + 1)?;
+        let (input, if_node_body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
         ctx.set_indentation(now_indentation);
 
         // dbg!(format!("parse_if_block elif {:?}", input));
         // elif
-        let (input, elif_nodes) = many0(|input: &'a str| {
-            // 略过空行
-            let (input, _) = many0(|input: &'a str| { Parser::parse_blank_line(ctx, input) })(input)?;
-
-
-            let (input, _) = delimited(space0, tag("elif"), space0)(input)?;
-            let (input, check_exp) = Parser::parse_expr(input)?;
-            let (input, _) = delimited(space0, tag(":"), space0)(input)?;
-            let (input, body) = Parser::parse_block(ctx, input, ctx.get_indentation() + 1)?;
+        let (input, elif_nodes) = many0(|input: Span<'a>| {
+            let (input, _) = terminated(
+                |input: Span<'a>| { Parser::parse_clause_head(ctx, input, "elif", now_indentation) },
+                space0,
+            )(input)?;
+            let (input, check_exp) = cut(Parser::expect("condition after 'elif'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+            let (input, _) = cut(Parser::expect("':' after condition", delimited(space0, tag(":"), space0)))(input)?;
+            let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
             ctx.set_indentation(now_indentation);
             Ok((input, (check_exp, body)))
         })(input)?;
 
         // dbg!(format!("parse_if_block else {:?}", input));
         // else
-        let (input, else_node) = opt(|input: &'a str| {
-            // 略过空行
-            let (input, _) = many0(|input: &'a str| { Parser::parse_blank_line(ctx, input) })(input)?;
-            // dbg!(format!("parse_if_block else parse_blank_line end {:?}", input));
-
-
-            let (input, _) = delimited(space0, tag("else"), space0)(input)?;
-            let (input, _) = delimited(space0, tag(":"), space0)(input)?;
+        let (input, else_node) = opt(|input: Span<'a>| {
+            let (input, _) = terminated(
+                |input: Span<'a>| { Parser::parse_clause_head(ctx, input, "else", now_indentation) },
+                space0,
+            )(input)?;
+            let (input, _) = cut(Parser::expect("':' after 'else'", delimited(space0, tag(":"), space0)))(input)?;
 
             // dbg!(format!("parse_if_block else keyword end {:?}", input));
 
-            let (input, body) = Parser::parse_block(ctx, input, ctx.get_indentation() + 1)?;
+            let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
             ctx.set_indentation(now_indentation);
 
             // dbg!(format!("parse_if_block else parse_block end {:?}", input));
@@ -341,132 +931,355 @@ impl<'a> Parser {
         })(input)?;
 
         // dbg!(format!("parse_if_block end {:?}", input));
-        Ok((input, Node::If {
+        let last_body = else_node.as_ref().or(elif_nodes.last().map(|(_, body)| body)).unwrap_or(&if_node_body);
+        let span = Parser::block_span(header, last_body);
+        let kind = NodeKind::If {
             if_node: Box::new((if_check_exp, if_node_body)),
             elif_nodes,
             else_node,
-        }))
+        };
+        Ok((input, Node::new(kind, span)))
     }
 
-    pub fn parse_for_block(ctx: &Parser, input: &'a str) -> IResult<&'a str, Node> {
-        let (input, _) = delimited(space0, tag("for"), space0)(input)?;
-        let (input, item_var_name) = Parser::parse_name(input)?;
-        let (input, _) = delimited(space0, tag("in"), space0)(input)?;
-        let (input, source_exp) = Parser::parse_expr(input)?;
-        let (input, _) = delimited(space0, tag(":"), space0)(input)?;
-        let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
+    pub fn parse_for_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = delimited(space0, Parser::keyword("for"), space0)(input)?;
+        let (input, item_var_name) = cut(Parser::expect("loop variable", |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+        let (input, _) = cut(Parser::expect("'in' after loop variable", delimited(space0, Parser::keyword("in"), space0)))(input)?;
+        let (input, source_exp) = cut(Parser::expect("expression after 'in'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let (input, _) = cut(Parser::expect("':' after loop source", delimited(space0, tag(":"), space0)))(input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
 
-        let (input, body) = Parser::parse_block(ctx, input, ctx.get_indentation() + 1)?;
-        Ok((input, Node::For {
+        let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
+        let span = Parser::block_span(header, &body);
+        let kind = NodeKind::For {
             item_var_name: item_var_name.to_string(),
             source_exp: Box::new(source_exp),
             body,
-        }))
+        };
+        Ok((input, Node::new(kind, span)))
+    }
+    pub fn parse_while_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = delimited(space0, Parser::keyword("while"), space0)(input)?;
+        let (input, check_exp) = cut(Parser::expect("condition after 'while'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let (input, _) = cut(Parser::expect("':' after condition", delimited(space0, tag(":"), space0)))(input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+
+        let (input, body) = Parser::parse_body(ctx, input, ctx.get_indentation() + 1)?;
+        let span = Parser::block_span(header, &body);
+        let kind = NodeKind::While {
+            check_exp: Box::new(check_exp),
+            body,
+        };
+        Ok((input, Node::new(kind, span)))
+    }
+
+    pub fn parse_try_block(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let now_indentation = ctx.get_indentation();
+        let start = input;
+        let (input, _) = Parser::keyword("try")(input)?;
+        let (input, _) = cut(Parser::expect("':' after 'try'", delimited(space0, tag(":"), space0)))(input)?;
+        let header = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        let (input, body) = Parser::parse_body(ctx, input, now_indentation + 1)?;
+        ctx.set_indentation(now_indentation);
+
+        // except和finally必须和try有相同的缩进, 避免被嵌套的try抢走
+        let parse_clause_head = |input: Span<'a>, keyword: &'static str| {
+            Parser::parse_clause_head(ctx, input, keyword, now_indentation)
+        };
+
+        let (input, except) = opt(|input: Span<'a>| {
+            let (input, _) = parse_clause_head(input, "except")?;
+            let (input, name) = opt(preceded(space1, |input: Span<'a>| { Parser::parse_name(input) }))(input)?;
+            let (input, _) = cut(Parser::expect("':' after 'except'", delimited(space0, tag(":"), space0)))(input)?;
+            let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+            let (input, body) = Parser::parse_body(ctx, input, now_indentation + 1)?;
+            ctx.set_indentation(now_indentation);
+            Ok((input, (name.map(|i| i.to_string()), body)))
+        })(input)?;
+
+        let (input, finally_body) = opt(|input: Span<'a>| {
+            let (input, _) = parse_clause_head(input, "finally")?;
+            let (input, _) = cut(Parser::expect("':' after 'finally'", delimited(space0, tag(":"), space0)))(input)?;
+            let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+            let (input, body) = Parser::parse_body(ctx, input, now_indentation + 1)?;
+            ctx.set_indentation(now_indentation);
+            Ok((input, body))
+        })(input)?;
+
+        let (except_name, except_body) = match except {
+            Some((name, body)) => (name, Some(body)),
+            None if finally_body.is_some() => (None, None),
+            // 只有try没有except和finally
+            None => {
+                return Err(nom::Err::Failure(ParseError {
+                    input,
+                    code: nom::error::ErrorKind::Fail,
+                    expected: Some("'except' or 'finally' after try block"),
+                }));
+            }
+        };
+        let last_body = finally_body.as_ref().or(except_body.as_ref()).unwrap_or(&body);
+        let span = Parser::block_span(header, last_body);
+        Ok((input, Node::new(NodeKind::Try { body, except_name, except_body, finally_body }, span)))
     }
-    pub fn parse_module(ctx: &Parser, input: &'a str) -> IResult<&'a str, Node> {
+
+    pub fn parse_raise(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, _) = alt((Parser::keyword("raise"), Parser::keyword("fail")))(input)?;
+        let (input, _) = space1(input)?;
+        let (input, value) = cut(Parser::expect("error value after 'raise'", |input: Span<'a>| { Parser::parse_expr(ctx, input) }))(input)?;
+        let span = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        Ok((input, Node::new(NodeKind::Raise(Box::new(value)), span)))
+    }
+
+    pub fn parse_loop_control(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let start = input;
+        let (input, kind) = alt((
+            value(NodeKind::Break, Parser::keyword("break")),
+            value(NodeKind::Continue, Parser::keyword("continue")),
+        ))(input)?;
+        let span = Parser::span(start, input);
+        let (input, _) = cut(|input: Span<'a>| { Parser::parse_crlf_or_ending(ctx, input) })(input)?;
+        Ok((input, Node::new(kind, span)))
+    }
+
+    pub fn parse_module(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
+        let source = input;
         let (input, value) = Parser::parse_block(ctx, input, 0)?;
-        // 清空一下剩余的空字符串避免后续检测错误
-        let (input, _) = alt((tag("\n"), space0))(input)?;
+        // 清空一下剩余的空字符串和注释避免后续检测错误
+        let (input, _) = many0(|input: Span<'a>| { Parser::parse_blank_line(ctx, input) })(input)?;
+        let (input, _) = space0(input)?;
+        let (input, _) = opt(|input: Span<'a>| { Parser::parse_comment(ctx, input) })(input)?;
         // dbg!(format!("parse input {:?} len = {:?}", input, input.len()));
-        Ok((input, Node::Module { body: value }))
+        let kind = NodeKind::Module { body: value, trivia: Parser::collect_comments(ctx, source.fragment()) };
+        Ok((input, Node::new(kind, Parser::span(source, input))))
     }
 
-    pub fn parse_item(ctx: &Parser, input: &'a str) -> IResult<&'a str, Node> {
+    pub fn parse_item(ctx: &Parser, input: Span<'a>) -> PResult<'a, Node> {
         // 略过空行
-        let (input, _) = many0(|input: &'a str| { Parser::parse_blank_line(ctx, input) })(input)?;
+        let (input, _) = many0(|input: Span<'a>| { Parser::parse_blank_line(ctx, input) })(input)?;
 
         // 检查缩进
-        let (input, indentation) = many0_count(alt((
-            tag("\t"),
-            tag("    "),
-        )))(input)?;
-        let (input, _) = not(tag(" "))(input)?;
+        let (input, indentation) = match ctx.measure_indentation(input) {
+            Ok(v) => v,
+            Err(_) => { return fail(input); }
+        };
         if indentation != ctx.get_indentation() {
             return fail::<_, Node, _>(input);
         }
 
         // 主解析器
         let (input, node) = alt((
-            |input: &'a str| {
-                let (input, node) = Parser::parse_set_attr(input)?;
+            |input: Span<'a>| {
+                let (input, node) = Parser::parse_set_attr(ctx, input)?;
                 let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
                 Ok((input, node))
             },
-            |input: &'a str| { Parser::parse_target_block(ctx, input) },
-            |input: &'a str| { Parser::parse_if_block(ctx, input) },
-            |input: &'a str| { Parser::parse_for_block(ctx, input) },
-            |input: &'a str| {
-                let (input, node) = Parser::parse_expr(input)?;
+            |input: Span<'a>| { Parser::parse_target_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_def_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_return(ctx, input) },
+            |input: Span<'a>| { Parser::parse_declare(ctx, input) },
+            |input: Span<'a>| { Parser::parse_include(ctx, input) },
+            |input: Span<'a>| { Parser::parse_import(ctx, input) },
+            |input: Span<'a>| { Parser::parse_if_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_for_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_while_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_try_block(ctx, input) },
+            |input: Span<'a>| { Parser::parse_raise(ctx, input) },
+            |input: Span<'a>| { Parser::parse_loop_control(ctx, input) },
+            |input: Span<'a>| {
+                let start = input;
+                let (input, node) = Parser::parse_expr(ctx, input)?;
+                // 只有`true`, `false`或`none`的一行是命令, 比如用`false`让target失败
+                if matches!(node.kind, NodeKind::Value(ValueData::Bool(_) | ValueData::None)) && !start.starts_with('(') {
+                    return fail(start);
+                }
                 let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
                 Ok((input, node))
             },
-            |input: &'a str| {
-                let (input, node) = Parser::parse_command(input)?;
+            |input: Span<'a>| {
+                let (input, node) = Parser::parse_command(ctx, input)?;
                 let (input, _) = Parser::parse_crlf_or_ending(ctx, input)?;
                 Ok((input, node))
             },
         ))(input)?;
 
         // 再次略过空行
-        let (input, _) = many0(|input: &'a str| { Parser::parse_blank_line(ctx, input) })(input)?;
+        let (input, _) = many0(|input: Span<'a>| { Parser::parse_blank_line(ctx, input) })(input)?;
 
         Ok((input, node))
     }
 
-    pub fn parse_block(ctx: &Parser, input: &'a str, need_indentation: usize) -> IResult<&'a str, Vec<Node>> {
-        many0(
-            alt((
-                |input: &'a str| {
-                    ctx.set_indentation(need_indentation);
-                    // dbg!(format!("parse_block iter item(indentation={}) start {:?}", ctx.get_indentation(), input));
-                    let (input, node) = Parser::parse_item(ctx, input)?;
-                    // dbg!(format!("parse_block iter item(indentation={}) end {:?}", ctx.get_indentation(), input));
-                    Ok((input, node))
-                },
-            ))
-        )(input)
+    pub fn parse_block(ctx: &Parser, input: Span<'a>, need_indentation: usize) -> PResult<'a, Vec<Node>> {
+        let mut input = input;
+        let mut body = Vec::new();
+        loop {
+            let (line, _) = many0(|input: Span<'a>| { Parser::parse_blank_line(ctx, input) })(input)?;
+            // 文件末尾没有换行的注释也当作空行
+            let (tail, _) = pair(space0, opt(|input: Span<'a>| { Parser::parse_comment(ctx, input) }))(line)?;
+            if tail.is_empty() {
+                return Ok((input, body));
+            }
+            let indentation = match ctx.measure_indentation(line) {
+                Ok((_, v)) => v,
+                Err(e) => {
+                    ctx.errors.borrow_mut().push(e);
+                    input = Parser::skip_statement(ctx, Parser::skip_line(line), need_indentation);
+                    continue;
+                }
+            };
+            if indentation < need_indentation {
+                return Ok((input, body));
+            }
+            if indentation > need_indentation {
+                let (rest, _) = space0(line)?;
+                ctx.errors.borrow_mut().push(SyntaxError::Invalid {
+                    offset: rest.location_offset(),
+                    width: 1,
+                    message: "unexpected indent".to_string(),
+                });
+                input = Parser::skip_statement(ctx, Parser::skip_line(line), need_indentation);
+                continue;
+            }
+
+            ctx.set_indentation(need_indentation);
+            match Parser::parse_item(ctx, input) {
+                Ok((rest, node)) => {
+                    body.push(node);
+                    input = rest;
+                }
+                Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                    // 从出错语句的下一行开始找相同缩进的语句继续解析,
+                    // 括号中的换行会让错误出现在后面的行, 那一行本身可能也有错误
+                    ctx.errors.borrow_mut().push(e.into_syntax_error());
+                    input = Parser::skip_statement(ctx, Parser::skip_line(line), need_indentation);
+                }
+                Err(e) => return Err(e),
+            }
+            ctx.set_indentation(need_indentation);
+        }
+    }
+
+    /// 跳过当前行剩下的内容
+    pub fn skip_line(input: Span<'a>) -> Span<'a> {
+        let (input, _) = not_line_ending::<_, ParseError>(input).unwrap_or((input, input));
+        let (input, _) = opt(line_ending::<_, ParseError>)(input).unwrap_or((input, None));
+        input
+    }
+
+    /// 跳过出错语句剩下的行: 空行, 缩进更深的行和跟在后面的elif, else, except, finally
+    pub fn skip_statement(ctx: &Parser, input: Span<'a>, indentation: usize) -> Span<'a> {
+        let mut input = input;
+        loop {
+            let (line, _) = space0::<_, ParseError>(input).unwrap_or((input, input));
+            let blank = line.is_empty() || line.starts_with('\n') || line.starts_with("\r\n") || line.starts_with('#');
+            // 缩进有错误的行留给parse_block报告, 跳过的行不能决定文件的缩进单位
+            let depth = match ctx.peek_indentation(input) {
+                Ok((_, depth, _)) => depth,
+                Err(_) if blank => 0,
+                Err(_) => { return input; }
+            };
+            let clause = alt((
+                Parser::keyword("elif"),
+                Parser::keyword("else"),
+                Parser::keyword("except"),
+                Parser::keyword("finally"),
+            ))(line).is_ok();
+            if line.is_empty() || !(blank || depth > indentation || (depth == indentation && clause)) {
+                return input;
+            }
+            input = Parser::skip_line(input);
+        }
+    }
+
+    /// 和语句开头缩进相同的`elif`, `else`, `except`或`finally`
+    pub fn parse_clause_head(ctx: &Parser, input: Span<'a>, keyword: &'static str, indentation: usize) -> PResult<'a, Span<'a>> {
+        let (input, _) = many0(|input: Span<'a>| { Parser::parse_blank_line(ctx, input) })(input)?;
+        match ctx.measure_indentation(input) {
+            Ok((input, depth)) if depth == indentation => Parser::keyword(keyword)(input),
+            _ => fail(input),
+        }
+    }
+
+    /// 语句块至少要有一条语句
+    pub fn parse_body(ctx: &Parser, input: Span<'a>, need_indentation: usize) -> PResult<'a, Vec<Node>> {
+        let errors = ctx.errors.borrow().len();
+        let (input, body) = Parser::parse_block(ctx, input, need_indentation)?;
+        // 语句块中的语句都有错误时已经报告过了
+        if body.is_empty() && ctx.errors.borrow().len() == errors {
+            return Err(nom::Err::Failure(ParseError {
+                input,
+                code: nom::error::ErrorKind::Many1,
+                expected: Some("indented block"),
+            }));
+        }
+        Ok((input, body))
     }
 }
 
 impl Default for Parser {
     fn default() -> Self {
-        Parser { this_line: Cell::new(1), indentation: Cell::new(0) }
+        Parser {
+            indentation: Cell::new(0),
+            comments: RefCell::new(BTreeMap::new()),
+            errors: RefCell::new(Vec::new()),
+            indent_unit: Cell::new(None),
+        }
     }
 }
 
+#[allow(dead_code)]
 pub fn parse_code(input: &str) -> TResult<Node> {
-    // let line_count = input.find('\n').unwrap_or(0)
+    parse_source(input, 0)
+}
+
+/// 解析一个源文件, 语法树中的位置都带上文件编号
+pub fn parse_source(input: &str, file: usize) -> TResult<Node> {
     let ctx = Parser::default();
-    match Parser::parse_module(&ctx, input) {
+    let result = Parser::parse_module(&ctx, Span::new_extra(input, file));
+    let mut errors = ctx.errors.take();
+    let node = match result {
         Ok((output, node)) => {
-            if output.len() > 0 {
-                Err(ErrorKind::Syntax {
-                    error_line_number: ctx.this_line(),
-                })
-            } else {
-                Ok(node)
+            if !output.is_empty() {
+                errors.push(SyntaxError::Invalid { offset: output.location_offset(), width: 1, message: "invalid syntax".to_string() });
             }
+            node
         }
-        Err(_) => {
-            Err(ErrorKind::Syntax {
-                error_line_number: ctx.this_line(),
-            })
+        Err(nom::Err::Failure(e) | nom::Err::Error(e)) => {
+            errors.push(e.into_syntax_error());
+            Node::from(NodeKind::Module { body: vec![], trivia: vec![] })
         }
+        Err(nom::Err::Incomplete(_)) => return Err(ErrorKind::Syntax("unexpected end of input".to_string())),
+    };
+    errors.sort_by_key(SyntaxError::offset);
+    let mut errors: Vec<ErrorKind> = errors.iter().map(|e| e.to_error(input)).collect();
+    match errors.len() {
+        0 => Ok(node),
+        1 => Err(errors.remove(0)),
+        _ => Err(ErrorKind::Multiple(errors)),
     }
 }
 
-pub fn parse_expr(input: &str) -> TResult<Node> {
-    match Parser::parse_expr(input) {
+#[allow(dead_code)]
+pub fn parse_expr(input: &str, file: usize) -> TResult<Node> {
+    let ctx = Parser::default();
+    match Parser::parse_expr(&ctx, Span::new_extra(input, file)) {
         Ok((output, node)) => {
-            if output.len() > (output.find(' ').unwrap_or(0) + output.find('\t').unwrap_or(0)) {
-                Err(ErrorKind::Syntax { error_line_number: 1 })
-            } else {
+            if output.trim().is_empty() {
                 Ok(node)
+            } else {
+                let e = SyntaxError::Invalid { offset: output.location_offset(), width: 1, message: "invalid syntax".to_string() };
+                Err(e.to_error(input))
             }
         }
-        Err(_) => {
-            Err(ErrorKind::Syntax { error_line_number: 1 })
-        }
+        Err(nom::Err::Failure(e) | nom::Err::Error(e)) => Err(e.into_syntax_error().to_error(input)),
+        Err(nom::Err::Incomplete(_)) => Err(ErrorKind::Syntax("unexpected end of input".to_string())),
     }
 }
 
@@ -474,53 +1287,168 @@ pub fn parse_expr(input: &str) -> TResult<Node> {
 mod test {
     use crate::parse::*;
 
+    /// 用字符串调用解析器, 剩余的输入也换回字符串方便比较
+    fn run<'a, O>(
+        mut parser: impl FnMut(Span<'a>) -> PResult<'a, O>,
+        input: &'a str,
+    ) -> Result<(&'a str, O), nom::Err<ParseError<'a>>> {
+        parser(Span::new_extra(input, 0)).map(|(rest, output)| (*rest.fragment(), output))
+    }
+
+    /// 解析出一个节点, 去掉位置信息之后再和手写的节点比较
+    fn node<'a>(
+        parser: impl FnMut(Span<'a>) -> PResult<'a, Node>,
+        input: &'a str,
+    ) -> Result<(&'a str, Node), nom::Err<ParseError<'a>>> {
+        run(parser, input).map(|(rest, output)| (rest, output.without_span()))
+    }
+
     #[test]
     fn test_parse_name_node() {
-        assert_eq!(Parser::parse_name_node("$11T"), Ok(("", Node::Name("11T".to_string()))));
+        assert_eq!(node(Parser::parse_name_node, "$11T"), Ok(("", Node::from(NodeKind::Name("11T".to_string())))));
+    }
+
+    #[test]
+    fn test_unicode_identifier() {
+        let ctx = Parser::default();
+        assert_eq!(node(Parser::parse_name_node, "$版本号 + 1"), Ok((" + 1", Node::from(NodeKind::Name("版本号".to_string())))));
+        assert_eq!(node(Parser::parse_name_node, "$模块.名字"), Ok(("", Node::from(NodeKind::Name("模块.名字".to_string())))));
+        let node = parse_code("target $构建:\n    $结果 = 1\n    打印 $结果\n").unwrap();
+        let NodeKind::Module { body, .. } = &node.kind else { panic!() };
+        let NodeKind::Target { name, body, .. } = &body[0].kind else { panic!() };
+        assert_eq!(name, "构建");
+        let NodeKind::Command { command, .. } = &body[1].kind else { panic!() };
+        assert_eq!(command, "打印");
+        // 命令名可以带`-`和`.`
+        let command = |code: &'static str| match run(|input| Parser::parse_command(&ctx, input), code) {
+            Ok((_, Node { kind: NodeKind::Command { command, args, .. }, .. })) => (command, args.len()),
+            v => panic!("{:?}", v),
+        };
+        assert_eq!(command("cargo-fmt --check"), ("cargo-fmt".to_string(), 1));
+        assert_eq!(command("x86_64-linux-gnu-gcc -c a.c"), ("x86_64-linux-gnu-gcc".to_string(), 2));
+        assert_eq!(command("python3.11 -m venv"), ("python3.11".to_string(), 2));
+        assert_eq!(command("rm -rf"), ("rm".to_string(), 1));
+        // 以关键字开头的命令名
+        assert_eq!(command("target-gen --out a"), ("target-gen".to_string(), 2));
+        assert_eq!(command("if-up eth0"), ("if-up".to_string(), 1));
+        assert_eq!(command("try.sh"), ("try.sh".to_string(), 0));
+        let node = parse_code("target $t:\n    target-gen:\n    if-up eth0\n    return-value\n").unwrap();
+        let NodeKind::Module { body, .. } = &node.kind else { panic!() };
+        let NodeKind::Target { body, .. } = &body[0].kind else { panic!() };
+        let commands: Vec<&str> = body.iter().map(|i| match &i.kind {
+            NodeKind::Command { command, .. } => command.as_str(),
+            v => panic!("{:?}", v),
+        }).collect();
+        assert_eq!(commands, ["target-gen", "if-up", "return-value"]);
     }
 
     #[test]
     fn test_value_parse() {
-        assert_eq!(Parser::parse_value("11"), Ok(("", Node::Value(ValueData::Int(11)))));
-        assert_eq!(Parser::parse_value("11.5"), Ok(("", Node::Value(ValueData::Float(11.5)))));
-        assert_eq!(Parser::parse_value(r###""11.5""###), Ok(("", Node::Value(ValueData::String("11.5".to_string())))));
+        assert_eq!(node(Parser::parse_value, "11"), Ok(("", Node::from(NodeKind::Value(ValueData::Int(11))))));
+        assert_eq!(node(Parser::parse_value, "11.5"), Ok(("", Node::from(NodeKind::Value(ValueData::Float(11.5))))));
+        assert_eq!(node(Parser::parse_value, r###""11.5""###), Ok(("", Node::from(NodeKind::Value(ValueData::String("11.5".to_string()))))));
+        assert_eq!(node(Parser::parse_value, "true"), Ok(("", Node::from(NodeKind::Value(ValueData::Bool(true))))));
+        assert_eq!(node(Parser::parse_value, "false"), Ok(("", Node::from(NodeKind::Value(ValueData::Bool(false))))));
+        assert_eq!(node(Parser::parse_value, "none"), Ok(("", Node::from(NodeKind::Value(ValueData::None)))));
+        assert!(node(Parser::parse_value, "trueish").is_err());
+    }
+
+    #[test]
+    fn test_number_parse() {
+        let value = |v: &'static str| node(Parser::parse_value, v).map(|(_, v)| v);
+        assert_eq!(value("0x1F"), Ok(Node::from(NodeKind::Value(ValueData::Int(31)))));
+        assert_eq!(value("-0o17"), Ok(Node::from(NodeKind::Value(ValueData::Int(-15)))));
+        assert_eq!(value("0b1010_1010"), Ok(Node::from(NodeKind::Value(ValueData::Int(170)))));
+        assert_eq!(value("1_000_000"), Ok(Node::from(NodeKind::Value(ValueData::Int(1_000_000)))));
+        assert_eq!(value("1e-3"), Ok(Node::from(NodeKind::Value(ValueData::Float(1e-3)))));
+        assert_eq!(value("2.5E2"), Ok(Node::from(NodeKind::Value(ValueData::Float(250.0)))));
+        assert_eq!(value(".5"), Ok(Node::from(NodeKind::Value(ValueData::Float(0.5)))));
+        assert_eq!(value("-9223372036854775808"), Ok(Node::from(NodeKind::Value(ValueData::Int(i64::MIN)))));
+        assert!(matches!(value("99999999999999999999"), Err(nom::Err::Failure(_))));
+        let e = parse_code("\n$a = [1,\n    0xFFFF_FFFF_FFFF_FFFF]\n").unwrap_err();
+        assert_eq!(e.root(), &ErrorKind::Syntax("number literal out of range: 0xFFFF_FFFF_FFFF_FFFF".to_string()));
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.width), (3, 5, 21));
+        assert_eq!(location.snippet, "    0xFFFF_FFFF_FFFF_FFFF]");
+        assert!(parse_code("$a = 1e999\n").is_err());
+    }
+
+    #[test]
+    fn test_string_escape_parse() {
+        assert_eq!(node(Parser::parse_value, r###""""###), Ok(("", Node::from(NodeKind::Value(ValueData::String("".to_string()))))));
+        assert_eq!(
+            node(Parser::parse_value, r###""a\"b\n\t\\\u{4e2d}""###),
+            Ok(("", Node::from(NodeKind::Value(ValueData::String("a\"b\n\t\\中".to_string()))))),
+        );
+        assert!(matches!(node(Parser::parse_value, r###""a\qb""###), Err(nom::Err::Failure(_))));
+        assert!(matches!(node(Parser::parse_value, r###""\u{110000}""###), Err(nom::Err::Failure(_))));
+        assert!(parse_code("message \"a\\qb\"\n").is_err());
+    }
+
+    #[test]
+    fn test_multiline_string_parse() {
+        let ctx = Parser::default();
+        let code = "\"\"\"\n        line 1\n          line 2\\t\n\n        line 3\n        \"\"\"";
+        assert_eq!(
+            node(Parser::parse_value, code),
+            Ok(("", Node::from(NodeKind::Value(ValueData::String("line 1\n  line 2\t\n\nline 3\n".to_string()))))),
+        );
+        assert_eq!(
+            node(Parser::parse_value, r###""""a "b" \"""c""""###),
+            Ok(("", Node::from(NodeKind::Value(ValueData::String("a \"b\" \"\"\"c".to_string()))))),
+        );
+        assert!(run(Parser::parse_multiline_string, "\"\"\"abc\"\"").is_err());
+        // 只有全角空格的行不能按字节截掉缩进
+        assert_eq!(
+            node(Parser::parse_value, "\"\"\"\n  a\n\u{3000}\n  b\n  \"\"\""),
+            Ok(("", Node::from(NodeKind::Value(ValueData::String("a\n\u{3000}\nb\n".to_string()))))),
+        );
+        assert_eq!(
+            node(|input| Parser::parse_command(&ctx, input), "cat -n <<< $text"),
+            Ok(("", Node::from(NodeKind::Command {
+                command: "cat".to_string(),
+                args: vec![Node::from(NodeKind::Value(ValueData::String("-n".to_string())))],
+                stdin: Some(Box::new(Node::from(NodeKind::Name("text".to_string())))),
+            }))),
+        );
     }
 
     #[test]
     fn test_expr_parse() {
+        let ctx = Parser::default();
         assert_eq!(
-            Parser::parse_expr("18 + 6 * 8.5 * (9+1)"),
+            node(|input| Parser::parse_expr(&ctx, input), "18 + 6 * 8.5 * (9+1)"),
             Ok((
                 "",
                 Node::create_expr(
-                    Node::Value(ValueData::Int(18)),
+                    Node::from(NodeKind::Value(ValueData::Int(18))),
                     OperatorData::Add,
                     Node::create_expr(
                         Node::create_expr(
-                            Node::Value(ValueData::Int(6)),
+                            Node::from(NodeKind::Value(ValueData::Int(6))),
                             OperatorData::Mul,
-                            Node::Value(ValueData::Float(8.5)),
+                            Node::from(NodeKind::Value(ValueData::Float(8.5))),
                         ),
                         OperatorData::Mul,
                         Node::create_expr(
-                            Node::Value(ValueData::Int(9)),
+                            Node::from(NodeKind::Value(ValueData::Int(9))),
                             OperatorData::Add,
-                            Node::Value(ValueData::Int(1)),
+                            Node::from(NodeKind::Value(ValueData::Int(1))),
                         ),
                     ),
                 ),
             ))
         );
-        assert_eq!(Parser::parse_expr("$test+6"), Ok(("",
+        assert_eq!(node(|input| Parser::parse_expr(&ctx, input), "$test+6"), Ok(("",
                                                       Node::create_expr(
-                                                          Node::Name("test".to_string()),
+                                                          Node::from(NodeKind::Name("test".to_string())),
                                                           OperatorData::Add,
-                                                          Node::Value(ValueData::Int(6)),
+                                                          Node::from(NodeKind::Value(ValueData::Int(6))),
                                                       )
         )));
         let code = r#""aa" + "bb" + 15 + " " + 10.5"#;
         println!("parse {:#?}", code);
-        let v = Parser::parse_expr(
+        let v = node(|input| Parser::parse_expr(&ctx, input), 
             code,
         );
         println!("IResult {:#?}", v);
@@ -528,35 +1456,219 @@ mod test {
 
     #[test]
     fn test_parse_command() {
-        let v = Parser::parse_command(
+        let ctx = Parser::default();
+        let v = node(|input| Parser::parse_command(&ctx, input), 
             r#"message test_command2 target_index ("aa" + "bb" + 15 + " " + 10.5)"#,
         );
         println!("{:#?}", v);
+        // 单独一行的`false`也是命令
+        let NodeKind::Module { body, .. } = parse_code("false\n(false)\n").unwrap().without_span().kind else { panic!() };
+        assert_eq!(body, [
+            Node::from(NodeKind::Command { command: "false".to_string(), args: vec![], stdin: None }),
+            Node::from(NodeKind::Value(ValueData::Bool(false))),
+        ]);
+        let words = node(|input| Parser::parse_command(&ctx, input), "echo none true false (none)").map(|(_, v)| v);
+        let Ok(Node { kind: NodeKind::Command { args, .. }, .. }) = words else { panic!("{:?}", words) };
+        let word = |v: &str| Node::from(NodeKind::Value(ValueData::String(v.to_string())));
+        assert_eq!(args, vec![word("none"), word("true"), word("false"), Node::from(NodeKind::Value(ValueData::None))]);
+        // 数字后面紧跟着其它字符时整个是一个单词
+        let words = node(|input| Parser::parse_command(&ctx, input), "message 1.2.3 2024-01-01 1 2.5 .5<<<\"\"").map(|(_, v)| v);
+        let Ok(Node { kind: NodeKind::Command { args, .. }, .. }) = words else { panic!("{:?}", words) };
+        let number = |v: ValueData| Node::from(NodeKind::Value(v));
+        assert_eq!(args, vec![
+            word("1.2.3"),
+            word("2024-01-01"),
+            number(ValueData::Int(1)),
+            number(ValueData::Float(2.5)),
+            number(ValueData::Float(0.5)),
+        ]);
+        // 参数中的`foo(1)`是普通的单词, 加上括号才是内置函数调用
+        assert_eq!(
+            node(|input| Parser::parse_command(&ctx, input), "message foo(1) (len([1]))").map(|(_, v)| v),
+            Ok(Node::from(NodeKind::Command {
+                command: "message".to_string(),
+                args: vec![
+                    Node::from(NodeKind::Value(ValueData::String("foo(1)".to_string()))),
+                    Node::from(NodeKind::NativeCall {
+                        name: "len".to_string(),
+                        args: vec![Node::from(NodeKind::List(vec![Node::from(NodeKind::Value(ValueData::Int(1)))]))],
+                    }),
+                ],
+                stdin: None,
+            })),
+        );
     }
 
     #[test]
     fn test_parse_set_attr() {
-        assert_eq!(Parser::parse_set_attr("$t = 15\n"), Ok((
+        let ctx = Parser::default();
+        assert_eq!(node(|input| Parser::parse_set_attr(&ctx, input), "$t = 15\n"), Ok((
             "\n",
-            Node::SetAttr {
+            Node::from(NodeKind::SetAttr {
                 name: "t".to_string(),
-                value: Box::new(Node::Value(ValueData::Int(15))),
-            },
+                value: Box::new(Node::from(NodeKind::Value(ValueData::Int(15)))),
+            }),
+        )));
+        assert_eq!(node(|input| Parser::parse_set_attr(&ctx, input), "$t ?= 15"), Ok((
+            "",
+            Node::from(NodeKind::SetDefault {
+                name: "t".to_string(),
+                value: Box::new(Node::from(NodeKind::Value(ValueData::Int(15)))),
+            }),
+        )));
+        assert_eq!(node(|input| Parser::parse_set_attr(&ctx, input), "$t[0] += 1"), Ok((
+            "",
+            Node::from(NodeKind::AddAssign {
+                target: Box::new(Node::from(NodeKind::Index {
+                    value: Box::new(Node::from(NodeKind::Name("t".to_string()))),
+                    index: Box::new(Node::from(NodeKind::Value(ValueData::Int(0)))),
+                })),
+                value: Box::new(Node::from(NodeKind::Value(ValueData::Int(1)))),
+            }),
+        )));
+        assert!(node(|input| Parser::parse_set_attr(&ctx, input), "$t[0] ?= 1").is_err());
+        assert!(node(|input| Parser::parse_set_attr(&ctx, input), "$t == 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_def() {
+        let ctx = Parser::default();
+        assert_eq!(node(|input| Parser::parse_item(&ctx, input), "def $add($a, $b):\n    return $a + $b\n"), Ok((
+            "",
+            Node::from(NodeKind::Function {
+                name: "add".to_string(),
+                params: vec!["a".to_string(), "b".to_string()],
+                body: vec![Node::from(NodeKind::Return(Some(Box::new(Node::create_expr(
+                    Node::from(NodeKind::Name("a".to_string())),
+                    OperatorData::Add,
+                    Node::from(NodeKind::Name("b".to_string())),
+                )))))],
+            }),
         )));
+        assert_eq!(node(|input| Parser::parse_expr(&ctx, input), "$add(1, $x)"), Ok((
+            "",
+            Node::from(NodeKind::Call {
+                name: "add".to_string(),
+                args: vec![Node::from(NodeKind::Value(ValueData::Int(1))), Node::from(NodeKind::Name("x".to_string()))],
+            }),
+        )));
+    }
+
+    #[test]
+    fn test_parse_subscript() {
+        let ctx = Parser::default();
+        assert_eq!(node(|input| Parser::parse_expr(&ctx, input), "$a[1:]"), Ok((
+            "",
+            Node::from(NodeKind::Slice {
+                value: Box::new(Node::from(NodeKind::Name("a".to_string()))),
+                start: Some(Box::new(Node::from(NodeKind::Value(ValueData::Int(1))))),
+                end: None,
+            }),
+        )));
+        assert_eq!(node(|input| Parser::parse_set_attr(&ctx, input), "$a[0] = 1"), Ok((
+            "",
+            Node::from(NodeKind::SetIndex {
+                target: Box::new(Node::from(NodeKind::Name("a".to_string()))),
+                index: Box::new(Node::from(NodeKind::Value(ValueData::Int(0)))),
+                value: Box::new(Node::from(NodeKind::Value(ValueData::Int(1)))),
+            }),
+        )));
+        assert!(node(|input| Parser::parse_set_attr(&ctx, input), "$a[0:1] = 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_try() {
+        let code = "try:\n    try:\n        a\n    except:\n        b\nfinally:\n    fail \"c\"\n";
+        let NodeKind::Module { body, .. } = parse_code(code).unwrap().without_span().kind else { panic!() };
+        let command = |name: &str| Node::from(NodeKind::Command { command: name.to_string(), args: vec![], stdin: None });
+        assert_eq!(body, vec![Node::from(NodeKind::Try {
+            body: vec![Node::from(NodeKind::Try {
+                body: vec![command("a")],
+                except_name: None,
+                except_body: Some(vec![command("b")]),
+                finally_body: None,
+            })],
+            except_name: None,
+            except_body: None,
+            finally_body: Some(vec![Node::from(NodeKind::Raise(Box::new(Node::from(NodeKind::Value(ValueData::String("c".to_string()))))))]),
+        })]);
+        assert!(parse_code("try:\n    a\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        let code = "# 文件头注释\n$a = 1 # 行尾注释\nif $a == 1:  # if\n    # 块内注释\n    message a#b # c\n# 结尾";
+        let node = parse_code(code).unwrap().without_span();
+        let NodeKind::Module { body, trivia } = node.kind else { panic!() };
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[0], Node::from(NodeKind::SetAttr {
+            name: "a".to_string(),
+            value: Box::new(Node::from(NodeKind::Value(ValueData::Int(1)))),
+        }));
+        let NodeKind::If { if_node, .. } = &body[1].kind else { panic!() };
+        assert_eq!(if_node.1, vec![Node::from(NodeKind::Command {
+            command: "message".to_string(),
+            args: vec![Node::from(NodeKind::Value(ValueData::String("a#b".to_string())))],
+            stdin: None,
+        })]);
+        let comment = |line: usize, text: &str, trailing: bool| Comment { line, text: text.to_string(), trailing };
+        assert_eq!(trivia, vec![
+            comment(1, " 文件头注释", false),
+            comment(2, " 行尾注释", true),
+            comment(3, " if", true),
+            comment(4, " 块内注释", false),
+            comment(5, " c", true),
+            comment(6, " 结尾", false),
+        ]);
     }
 
     #[test]
     fn test_parse_line() {
         let ctx = Parser::default();
-        assert_eq!(Parser::parse_item(&ctx, "$t = 15\n$t = 15"), Ok((
+        assert_eq!(node(|input| Parser::parse_item(&ctx, input), "$t = 15\n$t = 15"), Ok((
             "$t = 15",
-            Node::SetAttr {
+            Node::from(NodeKind::SetAttr {
                 name: "t".to_string(),
-                value: Box::new(Node::Value(ValueData::Int(15))),
-            },
+                value: Box::new(Node::from(NodeKind::Value(ValueData::Int(15)))),
+            }),
         )));
     }
 
+    #[test]
+    fn test_parse_continuation() {
+        let code = r###"
+cc -o main \
+    main.c \
+    util.c
+$flags = [
+    "-O2",  # 优化
+    # 警告
+    "-Wall",
+]
+$v = len(
+    $flags,  # 参数
+) + ($v
+    )
+"###;
+        let NodeKind::Module { body, trivia } = parse_code(code).unwrap().kind else { panic!() };
+        // 括号中的注释也记录下来
+        let comments: Vec<(usize, &str, bool)> = trivia.iter().map(|i| (i.line, i.text.as_str(), i.trailing)).collect();
+        assert_eq!(comments, [(6, " 优化", true), (7, " 警告", false), (11, " 参数", true)]);
+        assert_eq!(body.len(), 3);
+        let NodeKind::Command { args, .. } = &body[0].kind else { panic!() };
+        assert_eq!(args.len(), 4);
+        let NodeKind::SetAttr { value, .. } = &body[1].kind else { panic!() };
+        assert!(matches!(&value.kind, NodeKind::List(i) if i.len() == 2));
+
+        // 多行语句和空行之后的错误行号
+        let code = "$a = [\n    1,\n]\n\n\nmessage \\\n    a\n$b = (1\n";
+        assert_eq!(parse_code(code).unwrap_err().location().unwrap().line, 8);
+        let code = "message \"\"\"\n    a\n    \"\"\"\n$b = (1\n";
+        let e = parse_code(code).unwrap_err();
+        assert_eq!(e.kind(), "SyntaxError");
+        assert_eq!(e.location().unwrap().line, 4);
+    }
+
     #[test]
     fn print_build() {
         let code = r###"
@@ -606,4 +1718,99 @@ for i in [0, 1, 2, 3, 4, 5, 6]:
 "###;
         println!("{:#?}", parse_code(code));
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_expected_token() {
+        let error = |code: &str| {
+            let e = parse_code(code).unwrap_err();
+            let location = e.location().unwrap();
+            (e.message(), location.line, location.column)
+        };
+        assert_eq!(error("target $x\n    message x\n"), ("expected ':' after target name".to_string(), 1, 10));
+        assert_eq!(error("message a\ntarget:\n"), ("expected target name".to_string(), 2, 7));
+        assert_eq!(error("def $f($a:\n    return $a\n"), ("expected ')' after function parameters".to_string(), 1, 10));
+        assert_eq!(error("if $a\n    message a\n"), ("expected ':' after condition".to_string(), 1, 6));
+        assert_eq!(error("for $i [1]:\n    message $i\n"), ("expected 'in' after loop variable".to_string(), 1, 7));
+        assert_eq!(error("while $a:\nmessage a\n"), ("expected indented block".to_string(), 1, 10));
+        assert_eq!(error("try:\n    message a\nmessage b\n"), ("expected 'except' or 'finally' after try block".to_string(), 2, 14));
+        assert_eq!(error("$a = [1, 2\n"), ("expected ']' to close the list".to_string(), 1, 11));
+        assert_eq!(error("target $x:\n    $a = $f(1\n"), ("expected ')' to close the argument list".to_string(), 2, 14));
+        // 关键字只是命令名的一部分时仍然是命令
+        assert!(parse_code("format x\nifconfig\ntargets\n").is_ok());
+    }
+    #[test]
+    fn test_error_recovery() {
+        let code = "target $a\n    message a\n$b = [1\ntarget $c:\n    $d = = 1\n      message c\n    if $d\n        message d\n    else:\n        message e\n    message f\nmessage g\n";
+        let e = parse_code(code).unwrap_err();
+        let ErrorKind::Multiple(errors) = &e else { panic!("{:?}", e) };
+        let errors: Vec<(String, usize, usize)> = errors.iter()
+            .map(|e| (e.message(), e.location().unwrap().line, e.location().unwrap().column))
+            .collect();
+        assert_eq!(errors, vec![
+            ("expected ':' after target name".to_string(), 1, 10),
+            ("expected ']' to close the list".to_string(), 3, 8),
+            ("expected value after assignment".to_string(), 5, 9),
+            ("indentation of 6 spaces is not a multiple of 4, the indentation width of this file".to_string(), 6, 1),
+            ("expected ':' after condition".to_string(), 7, 10),
+        ]);
+        assert!(e.render().ends_with("error: found 5 errors"));
+        // 括号中的换行让错误出现在下一行时, 下一行的错误也要报告
+        let e = parse_code("target $t:\n    $b = (1\n    for $i in:\n        message $i\n    message done\n").unwrap_err();
+        let ErrorKind::Multiple(errors) = &e else { panic!("{:?}", e) };
+        let errors: Vec<(String, usize)> = errors.iter().map(|e| (e.message(), e.location().unwrap().line)).collect();
+        assert_eq!(errors, vec![
+            ("expected ')'".to_string(), 2),
+            ("expected expression after 'in'".to_string(), 3),
+        ]);
+        // 语句块的第一条语句出错时不再报告语句块为空
+        let e = parse_code("if $a:\n    $b = (1\n").unwrap_err();
+        assert_eq!(e.message(), "expected ')'");
+    }
+    #[test]
+    fn test_indentation() {
+        // 任意宽度的空格缩进, 由第一个缩进的行决定
+        let two = parse_code("if $a:\n  message a\n  if $b:\n    message b\n  else:\n    message c\nmessage d\n").unwrap().without_span();
+        let four = parse_code("if $a:\n    message a\n    if $b:\n        message b\n    else:\n        message c\nmessage d\n").unwrap().without_span();
+        let tab = parse_code("if $a:\n\tmessage a\n\tif $b:\n\t\tmessage b\n\telse:\n\t\tmessage c\nmessage d\n").unwrap().without_span();
+        assert_eq!(two, four);
+        assert_eq!(tab, four);
+        // 不能混用tab和空格
+        let e = parse_code("if $a:\n    message a\nif $b:\n\tmessage b\n").unwrap_err();
+        assert_eq!(e.message(), "indented with tabs but this file is indented with spaces");
+        assert_eq!(e.location().unwrap().line, 4);
+        let e = parse_code("if $a:\n\t message a\n").unwrap_err();
+        assert_eq!(e.message(), "inconsistent use of tabs and spaces in indentation");
+        let e = parse_code("if $a:\n    message a\n    if $b:\n      message b\n").unwrap_err();
+        assert_eq!(e.message(), "indentation of 6 spaces is not a multiple of 4, the indentation width of this file");
+        let e = parse_code("message a\n    message b\n").unwrap_err();
+        assert_eq!(e.message(), "unexpected indent");
+        // 出错语句后面被跳过的行不影响缩进单位
+        let e = parse_code("$a = )\n   junk\ntarget $t:\n    message t\n").unwrap_err();
+        assert_eq!(e.message(), "expected value after assignment");
+        // elif/else按缩进归属到对应的if
+        let node = parse_code("if $a:\n  if $b:\n    message b\nelse:\n  message c\n").unwrap();
+        let NodeKind::Module { body, .. } = &node.kind else { panic!() };
+        let NodeKind::If { else_node, .. } = &body[0].kind else { panic!() };
+        assert!(else_node.is_some());
+    }
+    #[test]
+    fn test_node_span() {
+        let code = "$a = [1, 2] + [3]\nif $a:\n    # 注释\n    message $a[0] + 1  # 行尾\n\n";
+        let node = parse_source(code, 3).unwrap();
+        let NodeKind::Module { body, .. } = &node.kind else { panic!() };
+        let text = |span: ast::Span| &code[span.start..span.end];
+        assert_eq!(text(body[0].span), "$a = [1, 2] + [3]");
+        assert_eq!((body[0].span.file, body[0].span.line, body[0].span.column), (3, 1, 1));
+        let NodeKind::SetAttr { value, .. } = &body[0].kind else { panic!() };
+        assert_eq!(text(value.span), "[1, 2] + [3]");
+        let NodeKind::Expr(expr) = &value.kind else { panic!() };
+        assert_eq!(text(expr.0.span), "[1, 2]");
+        assert_eq!(text(body[1].span), "if $a:\n    # 注释\n    message $a[0] + 1");
+        let NodeKind::If { if_node, .. } = &body[1].kind else { panic!() };
+        let command = &if_node.1[0];
+        assert_eq!(text(command.span), "message $a[0] + 1");
+        assert_eq!((command.span.line, command.span.column), (4, 5));
+        let NodeKind::Command { args, .. } = &command.kind else { panic!() };
+        assert_eq!(text(args[0].span), "$a[0]");
+        assert_eq!(text(args[1].span), "+");
+    }
+}
@@ -1,48 +1,542 @@
-use std::collections::HashMap;
-use crate::{dt, ast, parse};
-use crate::dt::{TResult, ErrorKind, WrapValueObject, TList, TNone, TTargetObject};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::process::{Command, Output, Stdio};
+use crate::{ast, parse, builtin};
+use crate::dt::{TResult, ErrorKind, Location, Value, TList, TTargetObject, TFunctionObject, TModuleObject, TVars};
 
-pub type LocalCommandFunctionType = fn(Vec<String>) -> TResult<WrapValueObject>;
+pub type LocalCommandFunctionType = fn(Vec<String>) -> TResult<Value>;
+pub type NativeFunctionType = fn(Vec<Value>) -> TResult<Value>;
+
+/// 函数调用的最大嵌套深度
+pub const MAX_CALL_DEPTH: usize = 64;
+
+/// 打断当前代码块执行的控制流信号
+#[derive(Debug, Clone)]
+pub enum Signal {
+    Return(Value),
+    Break,
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScopeKind {
+    Function,
+    Target,
+    Block,
+}
+
+/// 作用域链中的一层
+///
+/// 变量查找从最内层开始, 遇到函数或target作用域后直接跳到全局作用域,
+/// 所以函数和target之间互相看不到对方的变量
+#[derive(Debug, Clone)]
+pub struct Scope {
+    kind: ScopeKind,
+    vars: HashMap<String, Value>,
+    globals: HashSet<String>,
+}
+
+impl Scope {
+    pub fn new(kind: ScopeKind) -> Self {
+        Self {
+            kind,
+            vars: HashMap::new(),
+            globals: HashSet::new(),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct RunSpace {
     local_commands: HashMap<String, LocalCommandFunctionType>,
-    vars: HashMap<String, WrapValueObject>,
+    native_functions: HashMap<String, NativeFunctionType>,
+    globals: TVars,
+    scopes: Vec<Scope>,
+    signal: Option<Signal>,
+    loop_depth: usize,
+    /// 正在执行的文件, 用于解析相对路径和检测循环引用
+    file_stack: Vec<PathBuf>,
+    included: HashSet<PathBuf>,
+    modules: HashMap<PathBuf, Value>,
+    /// 加载过的源代码, 语法树节点中的文件编号是这里的下标
+    sources: Arc<RwLock<Vec<Source>>>,
+    /// 只显示要执行的命令, 不真正执行
+    dry_run: bool,
+    /// 执行命令时在标准错误中输出命令和所在的位置
+    tracing: bool,
+}
+
+/// 一个源文件的路径和内容
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    /// 规范化之后的路径, 直接执行的代码没有路径
+    pub path: Option<PathBuf>,
+    pub code: String,
 }
 
 impl RunSpace {
-    pub fn set(&mut self, key: &str, value: WrapValueObject) {
-        self.vars.insert(key.to_string(), value.clone());
+    /// 给变量赋值
+    ///
+    /// 已经在块作用域中声明的变量直接修改, 否则写入最近的函数/target作用域,
+    /// 被`global`声明过的变量以及不在任何函数/target中的变量写入全局作用域
+    pub fn set(&mut self, key: &str, value: Value) {
+        if let Some(module) = self.get_module_of(key) {
+            let (_, name) = key.split_once('.').unwrap();
+            module.set(name, value);
+            return;
+        }
+        for scope in self.scopes.iter_mut().rev() {
+            match scope.kind {
+                ScopeKind::Block if !scope.vars.contains_key(key) => continue,
+                ScopeKind::Function | ScopeKind::Target if scope.globals.contains(key) => break,
+                _ => {
+                    scope.vars.insert(key.to_string(), value);
+                    return;
+                }
+            }
+        }
+        self.globals.write().unwrap().insert(key.to_string(), value);
+    }
+    pub fn get(&self, key: &str) -> Option<Value> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(v) = scope.vars.get(key) {
+                return Some(v.clone());
+            }
+            if scope.kind != ScopeKind::Block {
+                break;
+            }
+        }
+        if let Some(v) = self.globals.read().unwrap().get(key) {
+            return Some(v.clone());
+        }
+        let (ns, name) = key.split_once('.')?;
+        self.get(ns)?.get_attr(name)
+    }
+    /// `$ns.name`形式的名字所指向的模块
+    fn get_module_of(&self, key: &str) -> Option<TModuleObject> {
+        let (ns, _) = key.split_once('.')?;
+        self.get(ns)?.downcast_ref::<TModuleObject>().cloned()
+    }
+    /// 在最内层作用域中声明变量
+    pub fn declare_local(&mut self, key: &str, value: Value) {
+        match self.scopes.last_mut() {
+            Some(scope) => { scope.vars.insert(key.to_string(), value); }
+            None => { self.globals.write().unwrap().insert(key.to_string(), value); }
+        }
+    }
+    /// 将变量声明为全局变量, 不在任何函数/target中时不做任何事
+    pub fn declare_global(&mut self, key: &str) {
+        if let Some(scope) = self.scopes.iter_mut().rev().find(|i| i.kind != ScopeKind::Block) {
+            scope.vars.remove(key);
+            scope.globals.insert(key.to_string());
+        }
+    }
+    pub fn get_globals(&self) -> TVars {
+        self.globals.clone()
+    }
+    pub fn with_scope<T>(&mut self, kind: ScopeKind, f: impl FnOnce(&mut RunSpace) -> TResult<T>) -> TResult<T> {
+        self.scopes.push(Scope::new(kind));
+        let result = f(self);
+        self.scopes.pop();
+        result
+    }
+    /// 切换到另一个全局作用域(例如函数定义时所在的模块)中执行
+    pub fn with_globals<T>(&mut self, globals: TVars, f: impl FnOnce(&mut RunSpace) -> TResult<T>) -> TResult<T> {
+        let globals = std::mem::replace(&mut self.globals, globals);
+        let result = f(self);
+        self.globals = globals;
+        result
+    }
+    pub fn call_depth(&self) -> usize {
+        self.scopes.iter().filter(|i| i.kind == ScopeKind::Function).count()
     }
-    pub fn get(&self, key: &str) -> Option<WrapValueObject> {
-        self.vars.get(key).map(|v| { v.clone() })
+    /// 复制一个共享全局作用域的运行环境, 用于在另一个线程中执行target
+    pub fn fork(&self) -> Self {
+        Self {
+            scopes: Vec::new(),
+            signal: None,
+            loop_depth: 0,
+            ..self.clone()
+        }
+    }
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+    pub fn set_tracing(&mut self, tracing: bool) {
+        self.tracing = tracing;
+    }
+    /// 记录源代码, 返回分配的文件编号
+    ///
+    /// 同一个文件内容没有变化时复用之前的编号, 重复引入文件不会一直占用内存
+    pub fn add_source(&self, name: &str, path: Option<&Path>, code: &str) -> usize {
+        let mut sources = self.sources.write().unwrap();
+        if let Some(path) = path {
+            if let Some(id) = sources.iter().position(|i| i.path.as_deref() == Some(path) && i.code == code) {
+                return id;
+            }
+        }
+        sources.push(Source { name: name.to_string(), path: path.map(Path::to_path_buf), code: code.to_string() });
+        sources.len() - 1
+    }
+    /// 节点在源代码中的位置, 编号未知时返回None
+    pub fn location(&self, span: ast::Span) -> Option<Location> {
+        let sources = self.sources.read().unwrap();
+        let source = sources.get(span.file)?;
+        let mut location = Location::from_offset(source.code.as_str(), span.start, span.end - span.start);
+        location.file = source.name.clone();
+        Some(location)
+    }
+    /// 输出一条执行记录
+    pub fn trace(&self, span: ast::Span, event: &str) {
+        if self.tracing {
+            match self.location(span) {
+                Some(location) => eprintln!("[trace] {}: {}", location, event),
+                None => eprintln!("[trace] {}:{}: {}", span.line, span.column, event),
+            }
+        }
     }
     pub fn add_local_command(&mut self, name: &str, f: LocalCommandFunctionType) {
         self.local_commands.insert(name.to_string(), f);
     }
+    pub fn add_native_function(&mut self, name: &str, f: NativeFunctionType) {
+        self.native_functions.insert(name.to_string(), f);
+    }
 }
 
 impl Default for RunSpace {
     fn default() -> Self {
         let mut space = Self {
             local_commands: HashMap::new(),
-            vars: HashMap::default(),
+            native_functions: HashMap::new(),
+            globals: TVars::default(),
+            scopes: Vec::new(),
+            signal: None,
+            loop_depth: 0,
+            file_stack: Vec::new(),
+            included: HashSet::new(),
+            modules: HashMap::new(),
+            sources: Arc::default(),
+            dry_run: false,
+            tracing: false,
         };
         space.add_local_command(
             "message",
             |args| {
                 let v = args.join(" ");
                 println!("{}", v);
-                Ok(TNone::a_none())
+                Ok(Value::None)
             },
         );
+        builtin::load(&mut space);
         space
     }
 }
 
-pub fn exec_ast(ast: &ast::Node, space: &mut RunSpace) -> TResult<WrapValueObject> {
-    match ast {
-        ast::Node::Name(k) => {
+pub fn exec_body(body: &[ast::Node], space: &mut RunSpace) -> TResult<()> {
+    for i in body {
+        exec_ast(i, space)?;
+        if space.signal.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub fn exec_block(body: &[ast::Node], space: &mut RunSpace) -> TResult<()> {
+    space.with_scope(ScopeKind::Block, |space| exec_body(body, space))
+}
+
+/// 执行一次循环体, 返回值表示是否继续循环
+fn exec_loop_body(body: &[ast::Node], space: &mut RunSpace) -> TResult<bool> {
+    exec_body(body, space)?;
+    match space.signal {
+        Some(Signal::Break) => {
+            space.signal = None;
+            Ok(false)
+        }
+        Some(Signal::Continue) => {
+            space.signal = None;
+            Ok(true)
+        }
+        Some(Signal::Return(_)) => Ok(false),
+        None => Ok(true),
+    }
+}
+
+fn exec_for(item_var_name: &str, source_exp: &ast::Node, body: &[ast::Node], space: &mut RunSpace) -> TResult<()> {
+    let source_value = exec_ast(source_exp, space)?;
+    for v in source_value.t_get_iter()? {
+        // 循环变量只在本次循环的块作用域中可见
+        let next = space.with_scope(ScopeKind::Block, |space| {
+            space.declare_local(item_var_name, v);
+            exec_loop_body(body, space)
+        })?;
+        if !next {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn exec_list_comp(
+    element: &ast::Node,
+    item_var_name: &str,
+    source_exp: &ast::Node,
+    condition: Option<&ast::Node>,
+    space: &mut RunSpace,
+) -> TResult<Value> {
+    let source_value = exec_ast(source_exp, space)?;
+    let mut list = TList::new();
+    for v in source_value.t_get_iter()? {
+        // 和for循环一样, 循环变量只在推导式内部可见
+        space.with_scope(ScopeKind::Block, |space| {
+            space.declare_local(item_var_name, v);
+            if let Some(condition) = condition {
+                if !exec_ast(condition, space)?.to_bool()? {
+                    return Ok(());
+                }
+            }
+            list.push(exec_ast(element, space)?);
+            Ok(())
+        })?;
+    }
+    Ok(Value::from(list))
+}
+
+fn exec_while(check_exp: &ast::Node, body: &[ast::Node], space: &mut RunSpace) -> TResult<()> {
+    while exec_ast(check_exp, space)?.to_bool()? {
+        if !space.with_scope(ScopeKind::Block, |space| exec_loop_body(body, space))? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn exec_try(
+    body: &[ast::Node],
+    except_name: Option<&str>,
+    except_body: Option<&[ast::Node]>,
+    finally_body: Option<&[ast::Node]>,
+    space: &mut RunSpace,
+) -> TResult<()> {
+    let mut result = exec_block(body, space);
+    if let (Err(e), Some(except_body)) = (&result, except_body) {
+        let e = e.clone();
+        result = space.with_scope(ScopeKind::Block, |space| {
+            if let Some(name) = except_name {
+                space.declare_local(name, Value::object(e));
+            }
+            exec_body(except_body, space)
+        });
+    }
+    if let Some(finally_body) = finally_body {
+        // finally中没有打断执行时, 保留try/except中的return/break/continue,
+        // 否则以finally为准, 之前的错误也被丢弃
+        let signal = space.signal.take();
+        exec_block(finally_body, space)?;
+        if space.signal.is_some() {
+            return Ok(());
+        }
+        space.signal = signal;
+    }
+    result
+}
+
+/// 重新抛出捕获到的错误, 其它的值作为错误信息
+fn make_raise_err(value: &Value) -> TResult<ErrorKind> {
+    if let Some(e) = value.downcast_ref::<ErrorKind>() {
+        return Ok(e.clone());
+    }
+    Ok(ErrorKind::make_run_err(value.to_str()?.as_str()))
+}
+
+pub fn call_function(space: &mut RunSpace, f: &TFunctionObject, args: Vec<Value>) -> TResult<Value> {
+    if f.params.len() != args.len() {
+        let meg = format!(
+            "function {} takes {} arguments but {} were given",
+            f.name, f.params.len(), args.len(),
+        );
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+    if space.call_depth() >= MAX_CALL_DEPTH {
+        let meg = format!("maximum call depth ({}) exceeded in function {}", MAX_CALL_DEPTH, f.name);
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+
+    let loop_depth = std::mem::replace(&mut space.loop_depth, 0);
+    let result = space.with_globals(f.globals.clone(), |space| {
+        space.with_scope(ScopeKind::Function, |space| {
+            for (k, v) in f.params.iter().zip(args) {
+                space.declare_local(k, v);
+            }
+            exec_body(&f.body, space)
+        })
+    });
+    space.loop_depth = loop_depth;
+    let signal = space.signal.take();
+    result?;
+
+    match signal {
+        Some(Signal::Return(v)) => Ok(v),
+        _ => Ok(Value::None),
+    }
+}
+
+fn exec_command(
+    command: &str,
+    args: &[ast::Node],
+    stdin: Option<&ast::Node>,
+    span: ast::Span,
+    space: &mut RunSpace,
+) -> TResult<Value> {
+    let stdin = match stdin {
+        Some(v) => Some(exec_ast(v, space)?.to_str()?),
+        None => None,
+    };
+    if let Some(f) = get_function(space, command) {
+        if stdin.is_some() {
+            let meg = format!("function {} does not accept stdin", command);
+            return Err(ErrorKind::make_run_err(meg.as_str()));
+        }
+        let mut args_value = Vec::new();
+        for i in args {
+            args_value.push(exec_ast(i, space)?);
+        }
+        return call_function(space, &f, args_value);
+    }
+
+    let mut args_str = Vec::new();
+    for i in args {
+        let v = exec_ast(i, space)?;
+        let v = v.to_str()?;
+        args_str.push(v);
+    }
+
+    let mut out_str = args_str.clone();
+    out_str.insert(0, command.to_string());
+    let out_str = out_str.join(" ");
+    if space.dry_run {
+        // 只显示要执行的命令和它在代码中的位置
+        let location = space.location(span).map_or(String::new(), |i| format!("{}: ", i));
+        println!("{}{}", location, out_str);
+        return Ok(Value::None);
+    }
+    println!("{}", out_str);
+    space.trace(span, format!("command {}", out_str).as_str());
+
+    if let Some(f) = space.local_commands.get(command) {
+        if stdin.is_some() {
+            let meg = format!("command {} does not accept stdin", command);
+            return Err(ErrorKind::make_run_err(meg.as_str()));
+        }
+        return f(args_str);
+    }
+
+    let p = run_process(command, args_str, stdin);
+    match p {
+        Ok(v) if v.status.success() => {
+            // println!("{}", String::from_utf8(v.stdout)?);
+            Ok(Value::from(0_i64))
+        }
+        Ok(v) => {
+            Err(ErrorKind::ExitError {
+                command: command.to_string(),
+                code: v.status.code().map(|v| v as i64),
+            })
+        }
+        Err(e) => {
+            Err(ErrorKind::CommandError(command.to_string(), e.to_string()))
+        }
+    }
+}
+
+fn run_process(command: &str, args: Vec<String>, stdin: Option<String>) -> std::io::Result<Output> {
+    let mut p = Command::new(command);
+    p.args(args);
+    let Some(stdin) = stdin else {
+        return p.output();
+    };
+
+    let mut child = p.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut pipe = child.stdin.take().unwrap();
+    // 在单独的线程中写入, 避免子进程输出过多时互相等待
+    let writer = std::thread::spawn(move || pipe.write_all(stdin.as_bytes()));
+    let output = child.wait_with_output()?;
+    match writer.join() {
+        // 子进程不读取标准输入就退出时会出现BrokenPipe, 不算错误
+        Ok(Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(output),
+    }
+}
+
+fn exec_index(ast: &ast::Node, space: &mut RunSpace) -> TResult<i64> {
+    let v = exec_ast(ast, space)?;
+    v.as_int().ok_or_else(|| ErrorKind::type_err("int", &v))
+}
+
+fn get_function(space: &RunSpace, name: &str) -> Option<TFunctionObject> {
+    space.get(name).and_then(|v| v.downcast_ref::<TFunctionObject>().cloned())
+}
+
+fn exec_expr(left_node: &ast::Node, op: &ast::OperatorData, right_node: &ast::Node, space: &mut RunSpace) -> TResult<Value> {
+    let left_value = exec_ast(left_node, space)?;
+    let right_value = exec_ast(right_node, space)?;
+
+    match op {
+        ast::OperatorData::Eq => { left_value.t_eq(&right_value) }
+        ast::OperatorData::NotEq => { left_value.t_not_eq(&right_value) }
+        ast::OperatorData::Add => { left_value.t_add(&right_value) }
+        ast::OperatorData::Sub => { left_value.t_sub(&right_value) }
+        ast::OperatorData::Mul => { left_value.t_mul(&right_value) }
+        ast::OperatorData::Div => { left_value.t_div(&right_value) }
+    }
+}
+
+fn exec_slice(value: &ast::Node, start: Option<&ast::Node>, end: Option<&ast::Node>, space: &mut RunSpace) -> TResult<Value> {
+    let value = exec_ast(value, space)?;
+    let start = match start {
+        Some(v) => Some(exec_index(v, space)?),
+        None => None,
+    };
+    let end = match end {
+        Some(v) => Some(exec_index(v, space)?),
+        None => None,
+    };
+    value.t_get_slice(start, end)
+}
+
+fn exec_add_assign(target: &ast::Node, value: &ast::Node, space: &mut RunSpace) -> TResult<()> {
+    let value = exec_ast(value, space)?;
+    match &target.kind {
+        ast::NodeKind::Index { value: container, index } => {
+            let container = exec_ast(container, space)?;
+            let index = exec_index(index, space)?;
+            let v = container.t_get_index(index)?.t_add(&value)?;
+            container.t_set_index(index, v)?;
+        }
+        ast::NodeKind::Name(name) => {
+            let v = exec_ast(target, space)?.t_add(&value)?;
+            space.set(name, v);
+        }
+        _ => { return Err(ErrorKind::TypeError("invalid target for +=".to_string())); }
+    }
+    Ok(())
+}
+
+/// 执行一个节点, 出错时记录节点的位置
+pub fn exec_ast(ast: &ast::Node, space: &mut RunSpace) -> TResult<Value> {
+    exec_node(ast, space).map_err(|e| match space.location(ast.span) {
+        Some(location) => e.at(location),
+        None => e,
+    })
+}
+
+fn exec_node(ast: &ast::Node, space: &mut RunSpace) -> TResult<Value> {
+    match &ast.kind {
+        ast::NodeKind::Name(k) => {
             space.get(k).map_or_else(
                 || {
                     let meg = format!("key {} not found", k);
@@ -53,127 +547,227 @@ pub fn exec_ast(ast: &ast::Node, space: &mut RunSpace) -> TResult<WrapValueObjec
                 },
             )
         }
-        ast::Node::Value(v) => {
+        ast::NodeKind::Value(v) => {
             match v {
-                ast::ValueData::Int(i) => { Ok(WrapValueObject::from_box(Box::from(*i))) }
-                ast::ValueData::Float(i) => { Ok(WrapValueObject::from_box(Box::from(*i))) }
-                ast::ValueData::String(i) => { Ok(WrapValueObject::from_box(Box::new(i.clone()))) }
+                ast::ValueData::Int(i) => { Ok(Value::from(*i)) }
+                ast::ValueData::Float(i) => { Ok(Value::from(*i)) }
+                ast::ValueData::String(i) => { Ok(Value::String(i.clone())) }
+                ast::ValueData::Bool(i) => { Ok(Value::Bool(*i)) }
+                ast::ValueData::None => { Ok(Value::None) }
             }
         }
-        ast::Node::List(nodes) => {
+        ast::NodeKind::List(nodes) => {
             let mut l = TList::new();
             for i in nodes {
                 let v = exec_ast(i, space)?;
                 l.push(v);
             }
-            Ok(WrapValueObject::from_box(Box::new(l)))
+            Ok(Value::from(l))
         }
-        ast::Node::Expr(v) => {
+        ast::NodeKind::ListComp { element, item_var_name, source_exp, condition } => {
+            exec_list_comp(element, item_var_name, source_exp, condition.as_deref(), space)
+        }
+        ast::NodeKind::Expr(v) => {
             let (left_node, op, right_node) = v.as_ref();
-            let left_value = exec_ast(left_node, space)?;
-            let right_value = exec_ast(right_node, space)?;
-
-            match op {
-                ast::OperatorData::Eq => { left_value.t_eq(right_value) }
-                ast::OperatorData::NotEq => { left_value.t_not_eq(right_value) }
-                ast::OperatorData::Add => { left_value.t_add(right_value) }
-                ast::OperatorData::Sub => { left_value.t_sub(right_value) }
-                ast::OperatorData::Mul => { left_value.t_mul(right_value) }
-                ast::OperatorData::Div => { left_value.t_div(right_value) }
-            }
+            exec_expr(left_node, op, right_node, space)
         }
-        ast::Node::SetAttr { name, value } => {
+        ast::NodeKind::Index { value, index } => {
+            let value = exec_ast(value, space)?;
+            let index = exec_index(index, space)?;
+            value.t_get_index(index)
+        }
+        ast::NodeKind::Slice { value, start, end } => {
+            exec_slice(value, start.as_deref(), end.as_deref(), space)
+        }
+        ast::NodeKind::SetIndex { target, index, value } => {
+            let target = exec_ast(target, space)?;
+            let index = exec_index(index, space)?;
+            let value = exec_ast(value, space)?;
+            target.t_set_index(index, value)?;
+            Ok(Value::None)
+        }
+        ast::NodeKind::SetAttr { name, value } => {
             let value = exec_ast(value, space)?;
             space.set(name, value.clone());
-            Ok(TNone::a_none())
+            Ok(Value::None)
         }
-        ast::Node::Command { command, args } => {
-            let mut args_str = Vec::new();
-            for i in args {
-                let v = exec_ast(i, space)?;
-                let v = v.unwrap().to_str()?;
-                args_str.push(v);
+        ast::NodeKind::SetDefault { name, value } => {
+            if space.get(name).is_none() {
+                let value = exec_ast(value, space)?;
+                space.set(name, value);
             }
-
-            let mut out_str = args_str.clone();
-            out_str.insert(0, command.clone());
-            let out_str = out_str.join(" ");
-            println!("{}", out_str);
-
-            if let Some(f) = space.local_commands.get(command) {
-                return f(args_str);
+            Ok(Value::None)
+        }
+        ast::NodeKind::AddAssign { target, value } => {
+            exec_add_assign(target, value, space)?;
+            Ok(Value::None)
+        }
+        ast::NodeKind::Local { name, value } => {
+            let value = match value {
+                Some(v) => exec_ast(v, space)?,
+                None => Value::None,
+            };
+            space.declare_local(name, value);
+            Ok(Value::None)
+        }
+        ast::NodeKind::Global { name, value } => {
+            space.declare_global(name);
+            if let Some(v) = value {
+                let v = exec_ast(v, space)?;
+                space.set(name, v);
             }
-
-            let p = std::process::Command::new(command).args(args_str).output();
-            match p {
-                Ok(v) => {
-                    // println!("{}", String::from_utf8(v.stdout)?);
-                    let v = v.status.code().map_or(
-                        TNone::a_none(),
-                        |v| { WrapValueObject::from_box(Box::from(v as i64)) },
-                    );
-                    Ok(v)
-                }
-                Err(e) => {
-                    Err(ErrorKind::CommandError(command.to_string(), e.to_string()))
-                }
+            Ok(Value::None)
+        }
+        ast::NodeKind::Call { name, args } => {
+            let f = match space.get(name) {
+                Some(v) => v.downcast_ref::<TFunctionObject>().cloned().ok_or_else(|| {
+                    ErrorKind::TypeError(format!("'{}' object is not callable", v.type_name()))
+                })?,
+                None => return Err(ErrorKind::NameError(name.to_string())),
+            };
+            let mut args_value = Vec::new();
+            for i in args {
+                args_value.push(exec_ast(i, space)?);
             }
+            call_function(space, &f, args_value)
         }
-        ast::Node::Target { name, require, body } => {
+        ast::NodeKind::NativeCall { name, args } => {
+            let f = match space.native_functions.get(name) {
+                Some(f) => *f,
+                None => return Err(ErrorKind::NameError(name.to_string())),
+            };
+            let mut args_value = Vec::new();
+            for i in args {
+                args_value.push(exec_ast(i, space)?);
+            }
+            f(args_value)
+        }
+        ast::NodeKind::Command { command, args, stdin } => exec_command(command, args, stdin.as_deref(), ast.span, space),
+        ast::NodeKind::Target { name, require, body } => {
             let v = TTargetObject {
                 name: name.clone(),
                 require: require.clone(),
                 body: body.clone(),
+                globals: space.get_globals(),
+                span: ast.span,
+            };
+            space.set(name, Value::object(v));
+            Ok(Value::None)
+        }
+        ast::NodeKind::Function { name, params, body } => {
+            let v = TFunctionObject {
+                name: name.clone(),
+                params: params.clone(),
+                body: body.clone(),
+                globals: space.get_globals(),
             };
-            space.set(name, WrapValueObject::from_box(Box::new(v)));
-            Ok(TNone::a_none())
+            space.set(name, Value::object(v));
+            Ok(Value::None)
         }
-        ast::Node::If { if_node, elif_nodes, else_node } => {
+        ast::NodeKind::Return(value) => {
+            if space.call_depth() == 0 {
+                return Err(ErrorKind::make_run_err("'return' outside function"));
+            }
+            let value = match value {
+                Some(v) => exec_ast(v, space)?,
+                None => Value::None,
+            };
+            space.signal = Some(Signal::Return(value));
+            Ok(Value::None)
+        }
+        ast::NodeKind::If { if_node, elif_nodes, else_node } => {
             let (check_exp, body) = if_node.as_ref();
-            let v = exec_ast(check_exp, space)?;
             if exec_ast(check_exp, space)?.to_bool()? {
-                for i in body {
-                    exec_ast(i, space)?;
-                }
-                return Ok(TNone::a_none());
+                exec_block(body, space)?;
+                return Ok(Value::None);
             }
             for (check_exp, body) in elif_nodes {
                 if exec_ast(check_exp, space)?.to_bool()? {
-                    for i in body {
-                        exec_ast(i, space)?;
-                    }
-                    return Ok(TNone::a_none());
+                    exec_block(body, space)?;
+                    return Ok(Value::None);
                 }
             }
             if let Some(body) = else_node {
-                for i in body {
-                    exec_ast(i, space)?;
-                }
-                return Ok(TNone::a_none());
+                exec_block(body, space)?;
+                return Ok(Value::None);
             }
-            return Ok(TNone::a_none());
-        }
-        ast::Node::For { item_var_name, source_exp, body } => {
-            let source_value = exec_ast(source_exp, space)?;
-            let mut source_iter = source_value.t_get_iter()?;
-            while let Some(v) = (source_iter.t_iter_next()?) {
-                space.set(item_var_name.as_str(), v);
-                for i in body {
-                    exec_ast(i, space)?;
-                }
+            Ok(Value::None)
+        }
+        ast::NodeKind::For { item_var_name, source_exp, body } => {
+            space.loop_depth += 1;
+            let result = exec_for(item_var_name, source_exp, body, space);
+            space.loop_depth -= 1;
+            result?;
+            Ok(Value::None)
+        }
+        ast::NodeKind::While { check_exp, body } => {
+            space.loop_depth += 1;
+            let result = exec_while(check_exp, body, space);
+            space.loop_depth -= 1;
+            result?;
+            Ok(Value::None)
+        }
+        ast::NodeKind::Break => {
+            if space.loop_depth == 0 {
+                return Err(ErrorKind::make_run_err("'break' outside loop"));
+            }
+            space.signal = Some(Signal::Break);
+            Ok(Value::None)
+        }
+        ast::NodeKind::Continue => {
+            if space.loop_depth == 0 {
+                return Err(ErrorKind::make_run_err("'continue' not properly in loop"));
             }
-            Ok(TNone::a_none())
+            space.signal = Some(Signal::Continue);
+            Ok(Value::None)
         }
-        ast::Node::Module { body } => {
-            for i in body {
-                exec_ast(i, space)?;
+        ast::NodeKind::Include(path) => {
+            let path = exec_ast(path, space)?.to_str()?;
+            let path = canonicalize(&resolve_path(space, path.as_str()))?;
+            // 已经引入过的文件不再重复执行, 循环引用交给exec_file报错
+            if space.included.contains(&path) && !space.file_stack.contains(&path) {
+                return Ok(Value::None);
             }
-            Ok(TNone::a_none())
+            exec_file(&path, space)?;
+            space.included.insert(path);
+            Ok(Value::None)
+        }
+        ast::NodeKind::Try { body, except_name, except_body, finally_body } => {
+            exec_try(body, except_name.as_deref(), except_body.as_deref(), finally_body.as_deref(), space)?;
+            Ok(Value::None)
+        }
+        ast::NodeKind::Raise(value) => {
+            let value = exec_ast(value, space)?;
+            Err(make_raise_err(&value)?)
+        }
+        ast::NodeKind::Import { path, name } => {
+            let path = exec_ast(path, space)?.to_str()?;
+            let path = canonicalize(&resolve_path(space, path.as_str()))?;
+            let module = match space.modules.get(&path) {
+                Some(v) => v.clone(),
+                None => {
+                    let globals = exec_module(&path, space)?;
+                    let v = TModuleObject {
+                        name: name.clone(),
+                        path: path.display().to_string(),
+                        globals,
+                    };
+                    let v = Value::object(v);
+                    space.modules.insert(path, v.clone());
+                    v
+                }
+            };
+            space.set(name, module);
+            Ok(Value::None)
+        }
+        ast::NodeKind::Module { body, .. } => {
+            exec_body(body, space)?;
+            Ok(Value::None)
         }
     }
 }
 //
-// pub fn exec_code(input: &str, space: Option<&mut RunSpace>) -> TResult<WrapValueObject> {
+// pub fn exec_code(input: &str, space: Option<&mut RunSpace>) -> TResult<Value> {
 //     let ast = parse_code(input)?;
 //     match space {
 //         Some(v) => {
@@ -185,7 +779,7 @@ pub fn exec_ast(ast: &ast::Node, space: &mut RunSpace) -> TResult<WrapValueObjec
 //     }
 // }
 //
-// pub fn eval_code(input: &str, space: Option<&mut RunSpace>) -> TResult<WrapValueObject> {
+// pub fn eval_code(input: &str, space: Option<&mut RunSpace>) -> TResult<Value> {
 //     let ast = parse_expr(input)?;
 //     match space {
 //         Some(v) => {
@@ -198,44 +792,213 @@ pub fn exec_ast(ast: &ast::Node, space: &mut RunSpace) -> TResult<WrapValueObjec
 // }
 
 
-pub fn exec_code(input: &str, space: &mut RunSpace) -> TResult<WrapValueObject> {
-    let ast = parse::parse_code(input)?;
+#[allow(dead_code)]
+pub fn exec_code(input: &str, space: &mut RunSpace) -> TResult<Value> {
+    let file = space.add_source("", None, input);
+    let ast = parse::parse_source(input, file)?;
     exec_ast(&ast, space)
 }
 
-pub fn eval_code(input: &str, space: &mut RunSpace) -> TResult<WrapValueObject> {
-    let ast = parse::parse_expr(input)?;
+#[allow(dead_code)]
+pub fn eval_code(input: &str, space: &mut RunSpace) -> TResult<Value> {
+    let file = space.add_source("", None, input);
+    let ast = parse::parse_expr(input, file)?;
     exec_ast(&ast, space)
 }
 
-pub fn exec_target(space: &mut RunSpace, target: &str) -> TResult<WrapValueObject> {
-    let v = space.get(target);
-    match v {
-        Some(v) => {
-            let v = v.downcast_ref::<dt::TTargetObject>();
-            // let v = &*v.unwrap();
-            // let v = dt::downcast_ref::<dt::TTargetObject>(v);
-            match v {
-                Some(v) => {
-                    let v = v.clone();
-                    for i in v.require {
-                        exec_target(space, i.as_str())?;
-                    }
-                    for i in v.body {
-                        exec_ast(&i, space)?;
-                    }
-                    Ok(dt::TNone::a_none())
-                }
-                None => Err(ErrorKind::TypeError)
+/// 相对路径以当前正在执行的文件所在的目录为基准
+fn resolve_path(space: &RunSpace, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    match space.file_stack.last().and_then(|i| i.parent()) {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn canonicalize(path: &Path) -> TResult<PathBuf> {
+    std::fs::canonicalize(path).map_err(|e| {
+        let meg = format!("open file({}) failed: {}", path.display(), e);
+        ErrorKind::make_run_err(meg.as_str())
+    })
+}
+
+pub fn exec_file(path: &Path, space: &mut RunSpace) -> TResult<Value> {
+    let file = path.display().to_string();
+    let path = canonicalize(path)?;
+    if let Some(i) = space.file_stack.iter().position(|i| *i == path) {
+        let chain: Vec<String> = space.file_stack[i..].iter()
+            .chain([&path])
+            .map(|i| i.display().to_string())
+            .collect();
+        let meg = format!("include cycle detected: {}", chain.join(" -> "));
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+    let code = std::fs::read_to_string(&path).map_err(|e| {
+        let meg = format!("open file({}) failed: {}", path.display(), e);
+        ErrorKind::make_run_err(meg.as_str())
+    })?;
+
+    let id = space.add_source(file.as_str(), Some(&path), code.as_str());
+    let ast = parse::parse_source(code.as_str(), id).map_err(|e| e.in_file(file.as_str()))?;
+    space.file_stack.push(path);
+    let result = exec_ast(&ast, space);
+    space.file_stack.pop();
+    result
+}
+
+/// 在新的全局作用域中执行文件, 返回执行后的全局作用域
+fn exec_module(path: &Path, space: &mut RunSpace) -> TResult<TVars> {
+    let globals = TVars::default();
+    let scopes = std::mem::take(&mut space.scopes);
+    let included = std::mem::take(&mut space.included);
+    let loop_depth = std::mem::replace(&mut space.loop_depth, 0);
+    let result = space.with_globals(globals.clone(), |space| exec_file(path, space));
+    space.scopes = scopes;
+    space.included = included;
+    space.loop_depth = loop_depth;
+    result?;
+    Ok(globals)
+}
+
+/// 找到名字对应的target, 同时返回用于区分不同target的编号
+fn lookup_target(space: &RunSpace, target: &str) -> TResult<(usize, TTargetObject)> {
+    let obj = space.get(target).ok_or_else(|| ErrorKind::NameError(target.to_string()))?;
+    match (&obj, obj.downcast_ref::<TTargetObject>()) {
+        (Value::Object(v), Some(target)) => Ok((Arc::as_ptr(v) as *const () as usize, target.clone())),
+        _ => Err(ErrorKind::type_err("target", &obj)),
+    }
+}
+
+/// 只执行target的内容, 不执行依赖
+fn exec_target_body(space: &mut RunSpace, v: &TTargetObject, target: &str) -> TResult<()> {
+    space.trace(v.span, format!("target {}", v.name).as_str());
+    // target内容在target定义时所在的模块中执行
+    space.with_globals(v.globals.clone(), |space| {
+        space.with_scope(ScopeKind::Target, |space| exec_body(&v.body, space))
+    }).map_err(|e| e.in_target(target))
+}
+
+#[allow(dead_code)]
+pub fn exec_target(space: &mut RunSpace, target: &str) -> TResult<Value> {
+    exec_targets(space, &[target.to_string()])?;
+    Ok(Value::None)
+}
+
+/// 按照依赖在前的顺序依次执行target, 共同的依赖只执行一次
+pub fn exec_targets(space: &mut RunSpace, targets: &[String]) -> TResult<()> {
+    for node in collect_all_targets(space, targets)? {
+        exec_target_node(space, &node)?;
+    }
+    Ok(())
+}
+
+/// 依赖图中的一个target, require是直接依赖的target在图中的下标
+struct TargetNode {
+    name: String,
+    target: TTargetObject,
+    require: Vec<usize>,
+    /// 第一次收集到这个target时依赖它的target链, 用于错误信息
+    chain: Vec<String>,
+}
+
+/// 执行target的内容, 错误中带上依赖它的target链
+fn exec_target_node(space: &mut RunSpace, node: &TargetNode) -> TResult<()> {
+    exec_target_body(space, &node.target, &node.name)
+        .map_err(|e| node.chain.iter().rev().fold(e, |e, i| e.in_target(i)))
+}
+
+fn collect_all_targets(space: &mut RunSpace, targets: &[String]) -> TResult<Vec<TargetNode>> {
+    let mut nodes = Vec::new();
+    let mut indexes = HashMap::new();
+    for target in targets {
+        collect_targets(space, target, &mut nodes, &mut indexes, &mut Vec::new())?;
+    }
+    Ok(nodes)
+}
+
+/// 按照依赖在前的顺序收集target, 每个target只出现一次
+fn collect_targets(
+    space: &mut RunSpace,
+    target: &str,
+    nodes: &mut Vec<TargetNode>,
+    indexes: &mut HashMap<usize, usize>,
+    stack: &mut Vec<(usize, String)>,
+) -> TResult<usize> {
+    let (id, v) = lookup_target(space, target)?;
+    if let Some(i) = indexes.get(&id) {
+        return Ok(*i);
+    }
+    if let Some(i) = stack.iter().position(|(i, _)| *i == id) {
+        let chain: Vec<&str> = stack[i..].iter().map(|(_, name)| name.as_str()).chain([target]).collect();
+        let meg = format!("target dependency cycle detected: {}", chain.join(" -> "));
+        return Err(ErrorKind::make_run_err(meg.as_str()));
+    }
+    stack.push((id, target.to_string()));
+    // 依赖在target定义时所在的模块中查找
+    let require = space.with_globals(v.globals.clone(), |space| {
+        v.require.iter().map(|i| collect_targets(space, i, nodes, indexes, stack)).collect::<TResult<Vec<_>>>()
+    });
+    stack.pop();
+    let require = require.map_err(|e| e.in_target(target))?;
+    let chain = stack.iter().map(|(_, name)| name.clone()).collect();
+    nodes.push(TargetNode { name: target.to_string(), target: v, require, chain });
+    indexes.insert(id, nodes.len() - 1);
+    Ok(nodes.len() - 1)
+}
+
+/// 每个target在单独的线程中执行, 共同的依赖只执行一次并且在依赖它的target之前完成.
+/// 返回第一个出错的target的错误, 依赖出错的target不会执行
+pub fn exec_targets_parallel(space: &RunSpace, targets: &[String]) -> TResult<()> {
+    let nodes = collect_all_targets(&mut space.fork(), targets)?;
+    // 每个target是否执行成功, None表示还没有结束
+    let finished = Mutex::new(vec![None; nodes.len()]);
+    let changed = Condvar::new();
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = nodes.iter().enumerate()
+            .map(|(index, node)| {
+                let mut space = space.fork();
+                let (finished, changed) = (&finished, &changed);
+                scope.spawn(move || {
+                    let ready = {
+                        let mut state = finished.lock().unwrap();
+                        loop {
+                            if node.require.iter().any(|i| state[*i] == Some(false)) {
+                                break false;
+                            }
+                            if node.require.iter().all(|i| state[*i] == Some(true)) {
+                                break true;
+                            }
+                            state = changed.wait(state).unwrap();
+                        }
+                    };
+                    let result = match ready {
+                        // 线程panic时也要通知等待的target
+                        true => std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            exec_target_node(&mut space, node)
+                        })).unwrap_or_else(|_| Err(ErrorKind::make_run_err("target thread panicked"))),
+                        false => Ok(()),
+                    };
+                    finished.lock().unwrap()[index] = Some(ready && result.is_ok());
+                    changed.notify_all();
+                    result
+                })
+            })
+            .collect();
+        let mut result = Ok(());
+        for i in handles {
+            let v = i.join().unwrap_or_else(|_| Err(ErrorKind::make_run_err("target thread panicked")));
+            if let (Ok(()), Err(e)) = (&result, v) {
+                result = Err(e);
             }
         }
-        None => Err(ErrorKind::NameError(target.to_string()))
-    }
+        result
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use crate::rt::{eval_code, exec_code, exec_target, RunSpace};
+    use crate::dt::{ErrorKind, Value};
+    use crate::rt::{eval_code, exec_code, exec_file, exec_target, exec_targets, exec_targets_parallel, RunSpace};
 
     #[test]
     fn test_eval_code() {
@@ -290,4 +1053,611 @@ for $i in [0, 1, 2, 3, 4, 5, 6]:
         println!("# test exec_target: ");
         println!("{:?}", exec_target(&mut space, "build"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_function() {
+        let code = r###"
+$x = "global"
+def $add($a, $b):
+    $x = "local"
+    return $a + $b
+def $fib($n):
+    if $n == 0:
+        return 0
+    if $n == 1:
+        return 1
+    return $fib($n - 1) + $fib($n - 2)
+def $first($items):
+    for $i in $items:
+        return $i
+    return "empty"
+def $nothing():
+    return
+$sum = $add(1, 2) * 2
+$f = $fib(10)
+$item = $first([7, 8, 9])
+$none = $nothing()
+add 1 2
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("sum").unwrap().to_str(), Ok("6".to_string()));
+        assert_eq!(space.get("f").unwrap().to_str(), Ok("55".to_string()));
+        assert_eq!(space.get("item").unwrap().to_str(), Ok("7".to_string()));
+        assert_eq!(space.get("none").unwrap().to_str(), Ok("None".to_string()));
+        assert_eq!(space.get("x").unwrap().to_str(), Ok("global".to_string()));
+        assert!(space.get("a").is_none());
+
+        assert!(eval_code("$add(1)", &mut space).is_err());
+        assert!(exec_code("return 1\n", &mut space).is_err());
+        let code = r###"
+def $forever($n):
+    return $forever($n + 1)
+$forever(0)
+"###;
+        assert!(matches!(
+            exec_code(code, &mut space).map_err(|e| e.root().clone()),
+            Err(ErrorKind::RuntimeError(meg)) if meg.starts_with("maximum call depth")
+        ));
+    }
+
+    #[test]
+    fn test_bool_and_none() {
+        let code = r###"
+$checked = []
+for $v in [true, false, none, 0, 1, 0.0, "", "a", [], [0]]:
+    if $v:
+        $checked = $checked + [true]
+    else:
+        $checked = $checked + [false]
+$same = true == true
+$different = false == none
+$debug = false
+while $debug:
+    $debug = false
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(
+            space.get("checked").unwrap().to_str().unwrap(),
+            "[true, false, false, false, true, false, false, true, false, true]",
+        );
+        assert_eq!(space.get("same").unwrap().to_str().unwrap(), "true");
+        assert_eq!(space.get("different").unwrap().to_str().unwrap(), "false");
+    }
+
+    #[test]
+    fn test_value_ops() {
+        let mut space = RunSpace::default();
+        // 和自己比较时只对列表加一次读锁
+        exec_code("$l = [1, [2]]\n", &mut space).unwrap();
+        let mut eval = |code: &str| eval_code(code, &mut space).unwrap().to_repr_str().unwrap();
+        assert_eq!(eval("1 + 2"), "3");
+        assert_eq!(eval("1 + 0.5"), "1.5");
+        assert_eq!(eval("3 / 2"), "1.5");
+        assert_eq!(eval(r#""a" + 1"#), r#""a1""#);
+        assert_eq!(eval("[1] + [2, 3]"), "[1, 2, 3]");
+        assert_eq!(eval("1 == 1.0"), "true");
+        assert_eq!(eval(r#"[1, "a"] == [1, "a"]"#), "true");
+        assert_eq!(eval(r#"[1] == ["1"]"#), "false");
+        assert_eq!(eval("none == none"), "true");
+        assert_eq!(eval("$l == $l"), "true");
+        assert_eq!(eval("$l[1] != $l[1]"), "false");
+        // 包含自己的列表
+        exec_code("$a = [1]\n$a[0] = $a\n$b = [1]\n$b[0] = $b\n$c = [$a]\n", &mut space).unwrap();
+        let mut eval = |code: &str| eval_code(code, &mut space).unwrap().to_repr_str().unwrap();
+        assert_eq!(eval("$a"), "[[...]]");
+        assert_eq!(eval("$c"), "[[[...]]]");
+        assert_eq!(eval("$a == $b"), "true");
+        assert_eq!(eval("$a == $c"), "true");
+        assert_eq!(eval("$c[0] != $a"), "false");
+        assert_eq!(
+            eval_code("9223372036854775807 + 1", &mut space).unwrap_err().root(),
+            &ErrorKind::make_run_err("integer overflow"),
+        );
+        assert_eq!(
+            eval_code("[1] - [1]", &mut space).unwrap_err().root(),
+            &ErrorKind::TypeError("unsupported operand types for -: 'list' and 'list'".to_string()),
+        );
+        assert_eq!(
+            eval_code("len(1)", &mut space).unwrap_err().root(),
+            &ErrorKind::TypeError("expected str or list, got int".to_string()),
+        );
+    }
+
+    #[test]
+    fn test_set_default_and_add_assign() {
+        let code = r###"
+$profile ?= "debug"
+$jobs ?= 4
+$flags = ["-Wall"]
+$all_flags = $flags
+$flags += ["-O2"]
+$name = "main"
+$name += ".o"
+$counts = [1, 2]
+$counts[-1] += 10
+"###;
+        let mut space = RunSpace::default();
+        space.set("profile", Value::from("release"));
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("profile").unwrap().to_str().unwrap(), "release");
+        assert_eq!(space.get("jobs").unwrap().to_str().unwrap(), "4");
+        assert_eq!(space.get("flags").unwrap().to_repr_str().unwrap(), r#"["-Wall", "-O2"]"#);
+        assert_eq!(space.get("all_flags").unwrap().to_repr_str().unwrap(), r#"["-Wall"]"#);
+        assert_eq!(space.get("name").unwrap().to_str().unwrap(), "main.o");
+        assert_eq!(space.get("counts").unwrap().to_str().unwrap(), "[1, 12]");
+        assert!(exec_code("$undefined_name += 1\n", &mut space).is_err());
+    }
+
+    #[test]
+    fn test_list_comp() {
+        let code = r###"
+$include_dirs = ["src", "include", "third_party"]
+$flags = ["-I" + $d for $d in $include_dirs]
+$local_flags = [
+    "-I" + $d
+    for $d in $include_dirs
+    if $d != "third_party"
+]
+$doubled = [$i * 2 for $i in range(3)]
+$d = "kept"
+$empty = [$d for $d in []]
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("flags").unwrap().to_str().unwrap(), r#"["-Isrc", "-Iinclude", "-Ithird_party"]"#);
+        assert_eq!(space.get("local_flags").unwrap().to_str().unwrap(), r#"["-Isrc", "-Iinclude"]"#);
+        assert_eq!(space.get("doubled").unwrap().to_str().unwrap(), "[0, 2, 4]");
+        assert_eq!(space.get("empty").unwrap().to_str().unwrap(), "[]");
+        assert_eq!(space.get("d").unwrap().to_str().unwrap(), "kept");
+    }
+
+    #[test]
+    fn test_loop_control() {
+        let code = r###"
+$i = 0
+$odd = []
+while $i != 10:
+    $i = $i + 1
+    if $i == 7:
+        break
+    if $i == 3:
+        continue
+    $odd = $odd + [$i]
+$found = 0
+for $j in [1, 2, 3, 4]:
+    for $k in [1, 2, 3]:
+        if $k == 2:
+            break
+        $found = $found + 1
+    if $j == 3:
+        break
+def $find($items, $target):
+    for $item in $items:
+        if $item == $target:
+            return "yes"
+    return "no"
+$r = $find([1, 2, 3], 2)
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("i").unwrap().to_str(), Ok("7".to_string()));
+        assert_eq!(space.get("odd").unwrap().to_str(), Ok("[1, 2, 4, 5, 6]".to_string()));
+        assert_eq!(space.get("found").unwrap().to_str(), Ok("3".to_string()));
+        assert_eq!(space.get("r").unwrap().to_str(), Ok("yes".to_string()));
+
+        assert!(exec_code("break\n", &mut space).is_err());
+        let code = r###"
+def $f():
+    continue
+for $i in [1]:
+    $f()
+"###;
+        assert!(exec_code(code, &mut space).is_err());
+    }
+
+    #[test]
+    fn test_index_and_slice() {
+        let code = r###"
+$list = [10, 20, [30, 40], 50]
+$s = "hello世界"
+$first = $list[0]
+$last = $list[-1]
+$nested = $list[2][1]
+$part = $list[1:3]
+$head = $list[:2]
+$tail = $list[-2:]
+$clamped = $list[1:100]
+$char = $s[5]
+$sub = $s[1:3]
+$list[0] = "a"
+$list[2][0] = "b"
+$list[-1] = $list[0] + $list[2][0]
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        let get = |k: &str| space.get(k).unwrap().to_str().unwrap();
+        assert_eq!(get("first"), "10");
+        assert_eq!(get("last"), "50");
+        assert_eq!(get("nested"), "40");
+        // 切片是浅拷贝, 与原列表共享子元素
+        assert_eq!(get("part"), r#"[20, ["b", 40]]"#);
+        assert_eq!(get("head"), "[10, 20]");
+        assert_eq!(get("tail"), r#"[["b", 40], 50]"#);
+        assert_eq!(get("clamped"), r#"[20, ["b", 40], 50]"#);
+        assert_eq!(get("char"), "世");
+        assert_eq!(get("sub"), "el");
+        assert_eq!(get("list"), r#"["a", 20, ["b", 40], "ab"]"#);
+
+        assert_eq!(
+            eval_code("$list[4]", &mut space).map_err(|e| e.root().clone()).err(),
+            Some(ErrorKind::IndexError { index: 4, length: 4 }),
+        );
+        assert_eq!(
+            eval_code("$s[-8]", &mut space).map_err(|e| e.root().clone()).err(),
+            Some(ErrorKind::IndexError { index: -8, length: 7 }),
+        );
+        assert!(exec_code("$list[9] = 1\n", &mut space).is_err());
+        assert!(eval_code(r#"$list["0"]"#, &mut space).is_err());
+    }
+
+    #[test]
+    fn test_multiline_string() {
+        let code = r###"
+def $f():
+    return """
+        first
+          second
+        """
+$text = $f()
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("text").unwrap().to_str().unwrap(), "first\n  second\n");
+        assert!(exec_code("message <<< \"a\"\n", &mut space).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_stdin() {
+        let code = r###"
+try:
+    sh <<< """
+        test "$0" = "sh" && exit 3
+        exit 1
+        """
+except $e:
+    $code = $e.code
+$text = "echo ok\nexit 0\n"
+$code2 = sh <<< $text
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("code").unwrap().to_str().unwrap(), "3");
+        assert_eq!(space.get("code2").unwrap().to_str().unwrap(), "0");
+    }
+
+    #[test]
+    fn test_try() {
+        let code = r###"
+$log = ["", ""]
+def $f($v):
+    try:
+        if $v == 0:
+            fail "v is " + $v
+        return $v
+    except $e:
+        $log[0] = $e.kind + ": " + $e.message
+        return -1
+    finally:
+        $log[1] = "finally " + $v
+$a = $f(1)
+$b = $f(0)
+for $i in [1, 2, 3]:
+    try:
+        $x = $undefined
+    finally:
+        break
+try:
+    try:
+        $x = $undefined
+    except $e:
+        raise $e
+except $e:
+    $kind = $e.kind
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert_eq!(space.get("a").unwrap().to_str().unwrap(), "1");
+        assert_eq!(space.get("b").unwrap().to_str().unwrap(), "-1");
+        assert_eq!(space.get("log").unwrap().to_str().unwrap(), r#"["RuntimeError: v is 0", "finally 0"]"#);
+        assert_eq!(space.get("kind").unwrap().to_str().unwrap(), "RuntimeError");
+
+        let code = "try:\n    fail \"boom\"\nfinally:\n    $cleaned = 1\n";
+        let mut space = RunSpace::default();
+        let e = exec_code(code, &mut space).map_err(|e| e.root().clone());
+        assert!(matches!(e, Err(ErrorKind::RuntimeError(meg)) if meg == "boom"));
+        assert_eq!(space.get("cleaned").unwrap().to_str().unwrap(), "1");
+    }
+
+    #[test]
+    fn test_scope() {
+        let code = r###"
+$name = "global"
+$count = 0
+for $i in [1, 2, 3]:
+    local $tmp = $i
+    $count = $count + $tmp
+    $last = $i
+target $a:
+    $name = "a"
+    $seen_a = $name
+    global $result = $seen_a
+target $b: $a
+    $name = "b"
+    global $count
+    $count = $count + 10
+def $f($n):
+    $name = "f"
+    global $calls
+    $calls = $n
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        assert!(space.get("i").is_none());
+        assert!(space.get("tmp").is_none());
+        assert_eq!(space.get("count").unwrap().to_str(), Ok("6".to_string()));
+        assert_eq!(space.get("last").unwrap().to_str(), Ok("3".to_string()));
+
+        exec_target(&mut space, "b").unwrap();
+        assert_eq!(space.get("name").unwrap().to_str(), Ok("global".to_string()));
+        assert!(space.get("seen_a").is_none());
+        assert_eq!(space.get("result").unwrap().to_str(), Ok("a".to_string()));
+        assert_eq!(space.get("count").unwrap().to_str(), Ok("16".to_string()));
+
+        eval_code("$f(5)", &mut space).unwrap();
+        assert_eq!(space.get("name").unwrap().to_str(), Ok("global".to_string()));
+        assert_eq!(space.get("calls").unwrap().to_str(), Ok("5".to_string()));
+    }
+
+    /// 测试用的临时目录, 离开作用域时删除
+    struct TempDir(std::path::PathBuf);
+
+    impl std::ops::Deref for TempDir {
+        type Target = std::path::Path;
+        fn deref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// 在临时目录中写入文件, 目录名带上进程号避免同时运行的测试互相干扰
+    fn write_files(dir_name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir = TempDir(std::env::temp_dir().join(format!("{}_{}", dir_name, std::process::id())));
+        let _ = std::fs::remove_dir_all(&*dir);
+        std::fs::create_dir_all(&*dir).unwrap();
+        for (name, code) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, code).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_parallel_targets() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Value>();
+        assert_send_sync::<RunSpace>();
+
+        let code = r###"
+$results = [0, 0, 0]
+def $sum($n):
+    $v = 0
+    for $i in range($n):
+        $v = $v + $i
+    return $v
+target $a:
+    $results[0] = $sum(100)
+target $b:
+    $results[1] = $sum(1000)
+target $c:
+    for $i in range(100):
+        $results[2] += 1
+target $fail:
+    raise "failed"
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        let targets: Vec<String> = ["a", "b", "c"].iter().map(|i| i.to_string()).collect();
+        exec_targets_parallel(&space, &targets).unwrap();
+        assert_eq!(space.get("results").unwrap().to_str().unwrap(), "[4950, 499500, 100]");
+
+        let targets = vec!["a".to_string(), "fail".to_string()];
+        let e = exec_targets_parallel(&space, &targets).unwrap_err();
+        assert_eq!(e.root(), &ErrorKind::make_run_err("failed"));
+    }
+
+    #[test]
+    fn test_parallel_shared_require() {
+        let code = r###"
+$runs = [0, 0, 0]
+target $clean:
+    $runs[0] += 1
+target $b: $clean
+    if $runs[0] != 1:
+        raise "clean has not finished"
+    $runs[1] += 1
+target $c: $clean $b
+    $runs[2] += 1
+target $broken: $fail
+    $runs[2] += 100
+target $fail:
+    raise "failed"
+target $x: $y
+    message x
+target $y: $x
+    message y
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        let targets: Vec<String> = ["b", "c", "clean"].iter().map(|i| i.to_string()).collect();
+        exec_targets_parallel(&space, &targets).unwrap();
+        // 共同的依赖只执行一次
+        assert_eq!(space.get("runs").unwrap().to_str().unwrap(), "[1, 1, 1]");
+
+        // 依赖出错时不执行依赖它的target
+        let e = exec_targets_parallel(&space, &["broken".to_string()]).unwrap_err();
+        assert_eq!(e.root(), &ErrorKind::make_run_err("failed"));
+        assert_eq!(space.get("runs").unwrap().to_str().unwrap(), "[1, 1, 1]");
+
+        let e = exec_targets_parallel(&space, &["x".to_string()]).unwrap_err();
+        assert_eq!(e.message(), "target dependency cycle detected: x -> y -> x");
+    }
+
+    #[test]
+    fn test_shared_require() {
+        let code = r###"
+$runs = [0, 0, 0]
+target $clean:
+    $runs[0] += 1
+target $b: $clean
+    $runs[1] += 1
+target $c: $clean $b
+    $runs[2] += 1
+target $x: $y
+    message x
+target $y: $x
+    message y
+"###;
+        let mut space = RunSpace::default();
+        exec_code(code, &mut space).unwrap();
+        // 不加-j时同样每个target只执行一次
+        let targets: Vec<String> = ["b", "c", "clean"].iter().map(|i| i.to_string()).collect();
+        exec_targets(&mut space, &targets).unwrap();
+        assert_eq!(space.get("runs").unwrap().to_str().unwrap(), "[1, 1, 1]");
+        exec_target(&mut space, "c").unwrap();
+        assert_eq!(space.get("runs").unwrap().to_str().unwrap(), "[2, 2, 2]");
+
+        let e = exec_target(&mut space, "x").unwrap_err();
+        assert_eq!(e.message(), "target dependency cycle detected: x -> y -> x");
+    }
+
+    #[test]
+    fn test_include_and_import() {
+        let dir = write_files("tentacle_test_include", &[
+            ("main.tentacle", r###"
+include "common.tentacle"
+include "common.tentacle"
+import "lib/lib.tentacle" as $lib
+$name = "main"
+$greeting = $lib.greet("main")
+$lib.counter = 10
+"###),
+            ("common.tentacle", r###"
+$count = $count + 1
+"###),
+            ("lib/lib.tentacle", r###"
+$name = "lib"
+$counter = 0
+def $greet($who):
+    return "hello " + $who + " from " + $name
+target $clean:
+    global $counter
+    $counter = $counter + 1
+target $build: $clean
+    global $built
+    $built = $name
+"###),
+        ]);
+        let mut space = RunSpace::default();
+        space.set("count", Value::Int(0));
+        exec_file(&dir.join("main.tentacle"), &mut space).unwrap();
+        let get = |space: &RunSpace, k: &str| space.get(k).unwrap().to_str().unwrap();
+        assert_eq!(get(&space, "count"), "1");
+        assert_eq!(get(&space, "greeting"), "hello main from lib");
+        assert_eq!(get(&space, "lib.counter"), "10");
+
+        exec_target(&mut space, "lib.build").unwrap();
+        assert_eq!(get(&space, "lib.counter"), "11");
+        assert_eq!(get(&space, "lib.built"), "lib");
+        assert!(space.get("built").is_none());
+        assert_eq!(get(&space, "name"), "main");
+
+        // 再次执行同一个文件时复用已经记录的源代码
+        let sources = space.sources.read().unwrap().len();
+        exec_file(&dir.join("main.tentacle"), &mut space).unwrap();
+        exec_file(&dir.join("lib/../main.tentacle"), &mut space).unwrap();
+        assert_eq!(space.sources.read().unwrap().len(), sources);
+    }
+
+    #[test]
+    fn test_error_render() {
+        let dir = write_files("tentacle_test_error_render", &[
+            ("syntax.tentacle", "$a = 1\nif $a:\n    $b = (1\n"),
+            ("target.tentacle", r###"
+target $clean:
+    $a = 1 + [2]
+target $build: $clean
+    message build
+"###),
+        ]);
+        let mut space = RunSpace::default();
+        let e = exec_file(&dir.join("syntax.tentacle"), &mut space).unwrap_err();
+        let file = dir.join("syntax.tentacle").display().to_string();
+        assert_eq!(e.render(), format!(
+            "error[SyntaxError]: expected ')'\n --> {}:3:12\n  |\n3 |     $b = (1\n  |            ^",
+            file,
+        ));
+
+        exec_file(&dir.join("target.tentacle"), &mut space).unwrap();
+        let e = exec_target(&mut space, "build").unwrap_err();
+        assert_eq!(e.targets(), ["build", "clean"]);
+        assert_eq!(
+            e.render(),
+            format!(
+                "error[TypeError]: unsupported operand types for +: 'int' and 'list'\n --> {}:3:10\n  |\n3 |     $a = 1 + [2]\n  |          ^^^^^^^\n  = target: build -> clean",
+                dir.join("target.tentacle").display(),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = write_files("tentacle_test_include_cycle", &[
+            ("a.tentacle", "include \"b.tentacle\"\n"),
+            ("b.tentacle", "import \"a.tentacle\" as $a\n"),
+        ]);
+        let mut space = RunSpace::default();
+        let e = exec_file(&dir.join("a.tentacle"), &mut space).map_err(|e| e.root().clone());
+        assert!(matches!(e, Err(ErrorKind::RuntimeError(meg)) if meg.starts_with("include cycle detected")));
+        assert!(exec_code("include \"not_exists.tentacle\"\n", &mut space).is_err());
+    }
+    #[test]
+    fn test_dry_run() {
+        let code = "target $build:\n    $a = 1\n    false $a\n    message done\n";
+        let mut space = RunSpace::default();
+        space.set_dry_run(true);
+        exec_code(code, &mut space).unwrap();
+        // `false`没有真正执行, 所以不会出错
+        exec_target(&mut space, "build").unwrap();
+
+        // 运行时错误指向出错的命令
+        space.set_dry_run(false);
+        let e = exec_target(&mut space, "build").unwrap_err();
+        let location = e.location().unwrap();
+        assert_eq!((location.line, location.column, location.width), (3, 5, 8));
+        assert_eq!(location.snippet, "    false $a");
+
+        // 单独一行的`false`执行命令, 让target失败
+        exec_code("target $check:\n    false\n    message unreachable\n", &mut space).unwrap();
+        let e = exec_target(&mut space, "check").unwrap_err();
+        assert_eq!(e.location().unwrap().snippet, "    false");
+    }
+}